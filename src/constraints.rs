@@ -0,0 +1,353 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Constraint of a byte position in the input, like MeCab's boundary constraints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// a token boundary or not
+    Any,
+    /// must be a token boundary
+    Token,
+    /// must be inside a token
+    Inside,
+}
+
+/// Constraints of the token boundaries and features for `Tokenizer::tokenize_with_constraints()` etc.
+///
+/// The positions are byte offsets in the input string.
+#[derive(Debug, Clone, Default)]
+pub struct BoundaryConstraints {
+    boundaries: BTreeMap<usize, Boundary>,
+    // start -> (end, feature pattern)
+    features: BTreeMap<usize, (usize, String)>,
+}
+
+impl BoundaryConstraints {
+    pub fn new() -> BoundaryConstraints {
+        BoundaryConstraints::default()
+    }
+
+    /// Input sentence and the constraints of MeCab's partial parsing format.
+    ///
+    /// Each line is a part of the sentence, and its start and end are token boundaries.
+    /// A line `surface\tfeature` is a token whose features match the feature pattern,
+    /// in which `*` matches any column.
+    pub fn from_partial(lines: &[&str]) -> (String, BoundaryConstraints) {
+        let mut s = String::new();
+        let mut constraints = BoundaryConstraints::new();
+        for line in lines.iter() {
+            let start = s.len();
+            match line.split_once('\t') {
+                Some((surface, feature)) => {
+                    s.push_str(surface);
+                    if feature.is_empty() {
+                        constraints.token(start..s.len());
+                    } else {
+                        constraints.feature(start..s.len(), feature);
+                    }
+                }
+                None => {
+                    s.push_str(line);
+                    constraints.boundary(start);
+                    constraints.boundary(s.len());
+                }
+            }
+        }
+        (s, constraints)
+    }
+
+    /// Constraint of the position.
+    pub fn get(&self, pos: usize) -> Boundary {
+        self.boundaries.get(&pos).copied().unwrap_or(Boundary::Any)
+    }
+
+    /// Set the constraint of the position.
+    pub fn set(&mut self, pos: usize, boundary: Boundary) -> &mut BoundaryConstraints {
+        self.boundaries.insert(pos, boundary);
+        self
+    }
+
+    /// The position must be a token boundary.
+    pub fn boundary(&mut self, pos: usize) -> &mut BoundaryConstraints {
+        self.set(pos, Boundary::Token)
+    }
+
+    /// The position must not be a token boundary.
+    pub fn no_boundary(&mut self, pos: usize) -> &mut BoundaryConstraints {
+        self.set(pos, Boundary::Inside)
+    }
+
+    /// The range must be one token.
+    pub fn token(&mut self, range: Range<usize>) -> &mut BoundaryConstraints {
+        self.boundary(range.start);
+        for pos in range.start + 1..range.end {
+            self.no_boundary(pos);
+        }
+        self.boundary(range.end)
+    }
+
+    /// The range must be one token whose features match the pattern.
+    ///
+    /// If no word in the dictionaries matches, the token has the pattern as the features.
+    pub fn feature(&mut self, range: Range<usize>, pattern: &str) -> &mut BoundaryConstraints {
+        self.features
+            .insert(range.start, (range.end, pattern.to_string()));
+        self.token(range)
+    }
+
+    /// Feature pattern of the token which starts at the position.
+    pub fn feature_at(&self, pos: usize) -> Option<&str> {
+        self.features.get(&pos).map(|(_, pattern)| pattern.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boundaries.is_empty() && self.features.is_empty()
+    }
+
+    // a token of start..end with the feature satisfies the constraints or not
+    pub(crate) fn allows(&self, start: usize, end: usize, feature: &[u8]) -> bool {
+        if self.get(start) == Boundary::Inside || self.get(end) == Boundary::Inside {
+            return false;
+        }
+        if self
+            .boundaries
+            .range(start + 1..end)
+            .any(|(_, &boundary)| boundary == Boundary::Token)
+        {
+            return false;
+        }
+        match self.features.get(&start) {
+            Some((_, pattern)) => match_feature(feature, pattern.as_bytes()),
+            None => true,
+        }
+    }
+
+    // the nearest end of a token which starts at the position
+    pub(crate) fn next_end(&self, s: &str, start: usize) -> usize {
+        let mut end = start;
+        for c in s[start..].chars() {
+            end += c.len_utf8();
+            if self.get(end) != Boundary::Inside {
+                break;
+            }
+        }
+        end
+    }
+}
+
+// the columns of the pattern are the same as the feature or `*`
+fn match_feature(feature: &[u8], pattern: &[u8]) -> bool {
+    feature
+        .split(|&b| b == b',')
+        .zip(pattern.split(|&b| b == b','))
+        .all(|(column, p)| p == b"*" || column == p)
+}
+
+#[cfg(test)]
+use super::tests::{assert_offsets, fixture_tokenizer};
+
+#[test]
+fn test_boundary_constraints() {
+    let mut constraints = BoundaryConstraints::new();
+    assert!(constraints.is_empty());
+    constraints.boundary(3).token(6..12);
+    assert_eq!(constraints.get(0), Boundary::Any);
+    assert_eq!(constraints.get(3), Boundary::Token);
+    assert_eq!(constraints.get(6), Boundary::Token);
+    assert_eq!(constraints.get(9), Boundary::Inside);
+    assert_eq!(constraints.get(12), Boundary::Token);
+
+    assert!(constraints.allows(0, 3, b""));
+    assert!(constraints.allows(3, 6, b""));
+    assert!(!constraints.allows(0, 6, b""));
+    assert!(constraints.allows(6, 12, b""));
+    assert!(!constraints.allows(6, 9, b""));
+    assert!(!constraints.allows(9, 12, b""));
+    assert!(!constraints.allows(3, 12, b""));
+    assert!(constraints.allows(12, 15, b""));
+    constraints.no_boundary(15);
+    assert!(!constraints.allows(12, 15, b""));
+    assert!(constraints.allows(12, 18, b""));
+    constraints.set(15, Boundary::Any);
+
+    constraints.feature(12..15, "名詞,*,一般");
+    assert_eq!(constraints.feature_at(12), Some("名詞,*,一般"));
+    assert!(constraints.allows(12, 15, "名詞,固有名詞,一般,*".as_bytes()));
+    assert!(constraints.allows(12, 15, "名詞".as_bytes()));
+    assert!(!constraints.allows(12, 15, "名詞,固有名詞,地域".as_bytes()));
+    assert!(!constraints.allows(12, 15, "動詞,自立".as_bytes()));
+
+    let s = "すもももも";
+    assert_eq!(constraints.next_end(s, 0), 3);
+    assert_eq!(constraints.next_end(s, 6), 12);
+}
+
+#[test]
+fn test_from_partial() {
+    let (s, constraints) =
+        BoundaryConstraints::from_partial(&["すもも", "もも\t名詞", "の", "うち\t"]);
+    assert_eq!(s, "すもももものうち");
+    assert_eq!(constraints.get(0), Boundary::Token);
+    assert_eq!(constraints.get(3), Boundary::Any);
+    assert_eq!(constraints.get(9), Boundary::Token);
+    assert_eq!(constraints.get(12), Boundary::Inside);
+    assert_eq!(constraints.get(15), Boundary::Token);
+    assert_eq!(constraints.get(18), Boundary::Token);
+    assert_eq!(constraints.get(21), Boundary::Inside);
+    assert_eq!(constraints.get(24), Boundary::Token);
+    assert_eq!(constraints.feature_at(9), Some("名詞"));
+    assert_eq!(constraints.feature_at(18), None);
+}
+
+#[test]
+fn test_parse_with_constraints() {
+    let tokenizer = fixture_tokenizer();
+    let parse = |s: &str, constraints: &BoundaryConstraints| {
+        let tokens = tokenizer.parse_with_constraints(s, constraints);
+        assert_offsets(s, &tokens);
+        tokens
+            .into_iter()
+            .map(|t| format!("{}/{}", t.surface, t.feature.split(',').next().unwrap()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let s = "東京都";
+    assert_eq!(parse(s, &BoundaryConstraints::new()), "東/名詞 京都/名詞");
+    assert_eq!(
+        parse(s, BoundaryConstraints::new().boundary(6)),
+        "東京/名詞 都/名詞"
+    );
+    assert_eq!(
+        parse(s, BoundaryConstraints::new().token(0..6)),
+        "東京/名詞 都/名詞"
+    );
+    // no word of the span
+    assert_eq!(
+        parse(s, BoundaryConstraints::new().token(0..9)),
+        "東京都/名詞"
+    );
+    assert_eq!(
+        parse(s, BoundaryConstraints::new().feature(3..9, "名詞,固有名詞")),
+        "東/名詞 京都/名詞"
+    );
+    // the token has the pattern if no word matches
+    let mut constraints = BoundaryConstraints::new();
+    constraints.feature(0..3, "接頭詞,名詞接続");
+    let tokens = tokenizer.parse_with_constraints(s, &constraints);
+    assert_eq!(tokens[0].surface, "東");
+    assert_eq!(tokens[0].feature, "接頭詞,名詞接続");
+    assert!(tokens[0].unknown);
+    assert_eq!(tokens[1].surface, "京都");
+
+    // all the N-best paths satisfy the constraints
+    let constraints = {
+        let mut constraints = BoundaryConstraints::new();
+        constraints.boundary(6);
+        constraints
+    };
+    let results: Vec<_> = tokenizer
+        .n_best_with_constraints("東京都すもも", &constraints)
+        .take(10)
+        .collect();
+    assert!(!results.is_empty());
+    for (tokens, cost) in results.iter() {
+        assert!(tokens.iter().any(|t| t.end == 6));
+        assert_eq!(*cost, tokenizer.path_cost(tokens));
+    }
+
+    // MeCab's partial parsing format
+    let (s, constraints) = BoundaryConstraints::from_partial(&[
+        "東京都",
+        "すもも\t名詞,一般",
+        "も",
+        "あわび\t名詞,固有名詞",
+    ]);
+    let tokens = tokenizer.parse_with_constraints(&s, &constraints);
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|t| (t.surface.as_str(), t.feature.as_str()))
+            .collect::<Vec<_>>()[2..],
+        [
+            ("すもも", "名詞,一般,*,*,*,*,すもも,スモモ,スモモ"),
+            ("も", "助詞,係助詞,*,*,*,*,も,モ,モ"),
+            ("あわび", "名詞,固有名詞"),
+        ]
+    );
+}
+
+#[test]
+fn test_tokenize_with_constraints() {
+    let tokenizer = fixture_tokenizer();
+    let wakati = |s: &str, constraints: &BoundaryConstraints| {
+        tokenizer
+            .tokenize_with_constraints(s, constraints)
+            .into_iter()
+            .map(|(surface, _)| surface)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let s = "すもももももももものうち";
+    assert_eq!(
+        wakati(s, &BoundaryConstraints::new()),
+        "すもも も もも も もも の うち"
+    );
+    // a boundary in すもも
+    assert_eq!(
+        wakati(s, BoundaryConstraints::new().boundary(6)),
+        "すも も も もも も もも の うち"
+    );
+    // no boundary between の and うち
+    assert_eq!(
+        wakati(s, BoundaryConstraints::new().no_boundary(30)),
+        "すもも も もも も もも のうち"
+    );
+    // one token over the boundaries of the best path
+    assert_eq!(
+        wakati(s, BoundaryConstraints::new().token(9..18)),
+        "すもも ももも も もも の うち"
+    );
+    assert_eq!(
+        wakati(s, BoundaryConstraints::new().token(3..9)),
+        "す もも も もも も もも の うち"
+    );
+    assert_eq!(
+        wakati(s, BoundaryConstraints::new().boundary(6).token(27..36)),
+        "すも も もも も もも も のうち"
+    );
+
+    assert_eq!(wakati("東京都", &BoundaryConstraints::new()), "東 京都");
+    assert_eq!(
+        wakati("東京都", BoundaryConstraints::new().boundary(6)),
+        "東京 都"
+    );
+    assert_eq!(
+        wakati("東京都", BoundaryConstraints::new().no_boundary(3)),
+        "東京 都"
+    );
+}
@@ -0,0 +1,77 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+// CSV columns of the dictionary sources and the features of the tokens.
+
+// split a csv line into at most `n` columns, the last column is the rest of the line
+pub(crate) fn split_csv(line: &str, n: usize) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    let mut chars = line.chars().peekable();
+    while columns.len() + 1 < n {
+        let mut column = String::new();
+        let quoted = chars.peek() == Some(&'"');
+        if quoted {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == '"' {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        column.push('"');
+                    } else {
+                        break;
+                    }
+                } else {
+                    column.push(c);
+                }
+            }
+        }
+        // read to the delimiter
+        let mut found = false;
+        for c in chars.by_ref() {
+            if c == ',' {
+                found = true;
+                break;
+            }
+            if !quoted {
+                column.push(c);
+            }
+        }
+        columns.push(column);
+        if !found {
+            return columns;
+        }
+    }
+    columns.push(chars.collect());
+    columns
+}
+
+#[test]
+fn test_split_csv() {
+    assert_eq!(split_csv("a,b,c", usize::MAX), vec!["a", "b", "c"]);
+    assert_eq!(split_csv("a,b,c,d", 3), vec!["a", "b", "c,d"]);
+    assert_eq!(
+        split_csv("\"a,b\",1,\"x\"\"y\"", usize::MAX),
+        vec!["a,b", "1", "x\"y"]
+    );
+    assert_eq!(split_csv("a,,", usize::MAX), vec!["a", "", ""]);
+}
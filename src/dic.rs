@@ -23,86 +23,141 @@
 */
 use memmap2::{Mmap, MmapOptions};
 use std::fs::File;
-use std::i16;
-use std::i32;
+use std::ops::Deref;
 use std::slice;
 use std::str;
 use std::sync::Arc;
-use std::u16;
-use std::u32;
 
 const MAX_GROUPING_SIZE: u32 = 24;
+const CHAR_TABLE_SIZE: usize = 0xffff;
 
+use super::Error;
 #[allow(unused_imports)]
 use super::*;
 
-fn unpack_u32(mmap: &Mmap, i: usize) -> u32 {
-    u32::from_le_bytes([mmap[i], mmap[i + 1], mmap[i + 2], mmap[i + 3]])
+/// Contents of a compiled dictionary file (sys.dic, unk.dic, char.bin or matrix.bin).
+///
+/// It is made from `&'static [u8]` (e.g. `include_bytes!`), `Vec<u8>` or `Arc<[u8]>`.
+#[derive(Clone)]
+pub struct DicData(Storage);
+
+#[derive(Clone)]
+enum Storage {
+    Mmap(Arc<Mmap>),
+    Static(&'static [u8]),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for DicData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Storage::Mmap(mmap) => mmap,
+            Storage::Static(bytes) => bytes,
+            Storage::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl From<&'static [u8]> for DicData {
+    fn from(bytes: &'static [u8]) -> DicData {
+        DicData(Storage::Static(bytes))
+    }
+}
+
+impl<const N: usize> From<&'static [u8; N]> for DicData {
+    fn from(bytes: &'static [u8; N]) -> DicData {
+        DicData(Storage::Static(bytes))
+    }
+}
+
+impl From<Vec<u8>> for DicData {
+    fn from(bytes: Vec<u8>) -> DicData {
+        DicData(Storage::Shared(bytes.into()))
+    }
 }
 
-fn unpack_i32(mmap: &Mmap, i: usize) -> i32 {
-    i32::from_le_bytes([mmap[i], mmap[i + 1], mmap[i + 2], mmap[i + 3]])
+impl From<Arc<[u8]>> for DicData {
+    fn from(bytes: Arc<[u8]>) -> DicData {
+        DicData(Storage::Shared(bytes))
+    }
+}
+
+fn mmap_file(path: &str) -> Result<DicData, Error> {
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mmap = unsafe { MmapOptions::new().map(&file) }.map_err(|e| Error::io(path, e))?;
+    Ok(DicData(Storage::Mmap(Arc::new(mmap))))
+}
+
+fn unpack_u32(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
 }
 
-fn unpack_u16(mmap: &Mmap, i: usize) -> u16 {
-    u16::from_le_bytes([mmap[i], mmap[i + 1]])
+fn unpack_i32(bytes: &[u8], i: usize) -> i32 {
+    i32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
 }
 
-fn unpack_i16(mmap: &Mmap, i: usize) -> i16 {
-    i16::from_le_bytes([mmap[i], mmap[i + 1]])
+fn unpack_u16(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([bytes[i], bytes[i + 1]])
 }
 
-fn unpack_string(mmap: &Mmap, offset: usize) -> String {
-    let mut end = offset;
-    while mmap[end] != 0 {
-        end += 1;
+fn unpack_i16(bytes: &[u8], i: usize) -> i16 {
+    i16::from_le_bytes([bytes[i], bytes[i + 1]])
+}
+
+fn unpack_string(path: &str, bytes: &[u8]) -> Result<String, Error> {
+    // null terminated string in bytes
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    match str::from_utf8(&bytes[..end]) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err(Error::InvalidUtf8 {
+            path: path.to_string(),
+        }),
     }
-    str::from_utf8(&mmap[offset..end]).unwrap().to_string()
 }
 
 fn utf8_to_ucs2(s: &[u8], index: usize) -> (u16, usize) {
     // utf8 to ucs2(16bit) code and it's array size
-    let ln = if (s[index] & 0b10000000) == 0b00000000 {
-        1
-    } else if (s[index] & 0b11100000) == 0b11000000 {
-        2
-    } else if (s[index] & 0b11110000) == 0b11100000 {
-        3
-    } else if (s[index] & 0b11111000) == 0b11110000 {
-        4
-    } else {
-        0
+    let ln = match s[index] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        // invalid byte
+        _ => return (0, 1),
     };
+    if index + ln > s.len() || s[index + 1..index + ln].iter().any(|&b| b & 0xC0 != 0x80) {
+        // broken sequence
+        return (0, 1);
+    }
 
     let mut ch32: u32;
     match ln {
-        1 => ch32 = s[index + 0] as u32,
+        1 => ch32 = s[index] as u32,
         2 => {
-            ch32 = ((s[index + 0] & 0x1F) as u32) << 6;
+            ch32 = ((s[index] & 0x1F) as u32) << 6;
             ch32 |= (s[index + 1] & 0x3F) as u32;
         }
         3 => {
-            ch32 = ((s[index + 0] & 0x0F) as u32) << 12;
+            ch32 = ((s[index] & 0x0F) as u32) << 12;
             ch32 |= ((s[index + 1] & 0x3F) as u32) << 6;
             ch32 |= (s[index + 2] & 0x3F) as u32;
         }
-        4 => {
-            ch32 = ((s[index + 0] & 0x07) as u32) << 18;
+        _ => {
+            ch32 = ((s[index] & 0x07) as u32) << 18;
             ch32 |= ((s[index + 1] & 0x3F) as u32) << 12;
             ch32 |= ((s[index + 2] & 0x3F) as u32) << 6;
             ch32 |= (s[index + 3] & 0x03F) as u32;
         }
-        _ => ch32 = 0,
     }
 
-    // ucs4 to ucs2
-    let ch16 = if ch32 < 0x10000 {
-        ch32 as u16
-    } else {
-        ((((ch32 - 0x10000) / 0x400 + 0xD800) << 8) + ((ch32 - 0x10000) % 0x400 + 0xDC00)) as u16
-    };
-
-    (ch16, ln)
+    // char.bin has no entries of the chars out of BMP (and U+FFFF),
+    // they are the same category as U+0000 (DEFAULT) like MeCab
+    if ch32 >= CHAR_TABLE_SIZE as u32 {
+        return (0, ln);
+    }
+    (ch32 as u16, ln)
 }
 
 //fn bytes_to_str(bytes: &[u8]) -> String {
@@ -121,9 +176,20 @@ pub struct DicEntry {
     pub feature_ptr: *const u8,
     pub feature_len: usize,
     pub skip: bool,
+    pub unknown: bool,
 }
 
+// SAFETY: the pointers are only read, and point to the input string, the dictionaries,
+// the runtime user dictionary and the constraints like lattice::Node.
+// The tokenizer moves the entries to the lattice or clears them at each position,
+// so the entries buffer of Workspace sent to another thread is always empty.
+unsafe impl Send for DicEntry {}
+
 impl DicEntry {
+    pub fn feature_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.feature_ptr, self.feature_len) }
+    }
+
     #[allow(dead_code)]
     fn original_string(&self) -> String {
         unsafe {
@@ -136,31 +202,44 @@ impl DicEntry {
 
 #[derive(Clone)]
 pub struct CharProperty {
-    pub mmap: Arc<Mmap>,
+    data: DicData,
     pub category_names: Vec<String>,
     pub offset: usize,
+    // unknown words longer than this are not grouped
+    pub max_grouping_size: u32,
 }
 
 impl CharProperty {
-    pub fn open(dic_path: &str) -> Result<CharProperty, std::io::Error> {
-        let file = File::open(dic_path)?;
-        let mmap = unsafe { Arc::new(MmapOptions::new().map(&file)?) };
+    pub fn open(dic_path: &str) -> Result<CharProperty, Error> {
+        CharProperty::from_data(dic_path, mmap_file(dic_path)?)
+    }
+
+    pub fn from_data(dic_path: &str, data: DicData) -> Result<CharProperty, Error> {
+        if data.len() < 4 {
+            return Err(Error::corrupt(dic_path, "file is too short"));
+        }
+        let num_categories = unpack_u32(&data, 0) as usize;
+        let offset = num_categories.saturating_mul(32).saturating_add(4);
+        if data.len() < offset.saturating_add(CHAR_TABLE_SIZE * 4) {
+            return Err(Error::corrupt(dic_path, "file is too short"));
+        }
         let mut category_names: Vec<String> = Vec::new();
-        let num_categories = unpack_u32(&mmap, 0);
         for i in 0..num_categories {
-            category_names.push(unpack_string(&mmap, (4 + i * 32) as usize));
+            let start = 4 + i * 32;
+            category_names.push(unpack_string(dic_path, &data[start..start + 32])?);
         }
 
         let char_property = CharProperty {
-            mmap: mmap,
-            category_names: category_names,
-            offset: (4 + num_categories * 32) as usize,
+            data,
+            category_names,
+            offset,
+            max_grouping_size: MAX_GROUPING_SIZE,
         };
         Ok(char_property)
     }
 
     pub fn get_char_info(&self, code_point: u16) -> (u32, u32, u32, u32, u32) {
-        let v = unpack_u32(&self.mmap, self.offset + (code_point as usize) * 4);
+        let v = unpack_u32(&self.data, self.offset + (code_point as usize) * 4);
         (
             (v >> 18) & 0b11111111,   // default_type
             v & 0b111111111111111111, // type
@@ -182,7 +261,7 @@ impl CharProperty {
             if ((1 << default_type) & t) != 0 {
                 i += ln;
                 char_count += 1;
-                if char_count > MAX_GROUPING_SIZE + 1 {
+                if char_count > self.max_grouping_size + 1 {
                     return -1;
                 }
             } else {
@@ -211,15 +290,23 @@ impl CharProperty {
         i as isize
     }
 
-    pub fn get_unknown_lengths(&self, s: &[u8]) -> (u32, Vec<usize>, bool) {
-        // get unknown word bytes length vector
-        let mut ln_vec: Vec<usize> = Vec::new();
+    // type and invoke always flag of the unknown words at the start of s
+    pub fn get_unknown_type(&self, s: &[u8]) -> (u32, bool) {
+        let (ch16, _) = utf8_to_ucs2(s, 0);
+        let (default_type, _, _, _, invoke) = self.get_char_info(ch16);
+        (default_type, invoke == 1)
+    }
+
+    pub fn get_unknown_lengths(&self, s: &[u8], mut f: impl FnMut(usize)) {
+        // call f with each unknown word bytes length
+        let mut found = false;
         let (ch16, first_ln) = utf8_to_ucs2(s, 0);
-        let (default_type, _, count, group, invoke) = self.get_char_info(ch16);
+        let (default_type, _, count, group, _) = self.get_char_info(ch16);
         if group != 0 {
             let ln = self.get_group_length(s, default_type);
             if ln > 0 {
-                ln_vec.push(ln as usize);
+                f(ln as usize);
+                found = true;
             }
         }
         if count != 0 {
@@ -228,54 +315,200 @@ impl CharProperty {
                 if ln < 0 {
                     break;
                 }
-                ln_vec.push(ln as usize);
+                f(ln as usize);
+                found = true;
             }
         }
 
-        if ln_vec.len() == 0 {
-            ln_vec.push(first_ln);
+        if !found {
+            f(first_ln);
         }
+    }
+}
 
-        // type, vector of length, invoke always flag
-        (default_type, ln_vec, invoke == 1)
+const DIC_MAGIC_ID: u32 = 0xef718f77;
+const DIC_VERSION: u32 = 102;
+const DIC_HEADER_SIZE: usize = 72;
+
+/// Type of a dictionary file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DicType {
+    Sys = 0,
+    Usr = 1,
+    Unk = 2,
+}
+
+impl DicType {
+    fn from_u32(v: u32) -> Option<DicType> {
+        match v {
+            0 => Some(DicType::Sys),
+            1 => Some(DicType::Usr),
+            2 => Some(DicType::Unk),
+            _ => None,
+        }
     }
 }
 
+/// Header information of a loaded dictionary, like MeCab's `dictionary_info()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DictionaryInfo {
+    /// path of the dictionary file
+    pub filename: String,
+    /// charset of the dictionary (e.g. "utf-8")
+    pub charset: String,
+    /// number of words
+    pub size: u32,
+    /// sys.dic, user dictionary or unk.dic
+    pub dictype: DicType,
+    /// left context id size
+    pub lsize: u32,
+    /// right context id size
+    pub rsize: u32,
+    /// dictionary format version
+    pub version: u32,
+}
+
+// (left id, right id, the lowest cost, number of feature columns) of a part of speech
+pub type Context = (u16, u16, i16, usize);
+
 #[derive(Clone)]
 pub struct MeCabDic {
-    mmap: Arc<Mmap>,
-    da_offset: u32,
-    token_offset: u32,
-    feature_offset: u32,
+    data: DicData,
+    pub info: DictionaryInfo,
+    lexsize: usize,
+    da_offset: usize,
+    da_size: usize,
+    token_offset: usize,
+    feature_offset: usize,
+    feature_size: usize,
 }
 
 impl MeCabDic {
-    pub fn open(dic_path: &str) -> Result<MeCabDic, std::io::Error> {
-        let file = File::open(dic_path)?;
-        let mmap = unsafe { Arc::new(MmapOptions::new().map(&file)?) };
-        // dic size unpack_u32(&mmap, 0) ^ 0xef718f77;
-        let _version = unpack_u32(&mmap, 4);
-        let _dictype = unpack_u32(&mmap, 8);
-        let _lexsize = unpack_u32(&mmap, 12);
-        let _lsize = unpack_u32(&mmap, 16);
-        let _rsize = unpack_u32(&mmap, 20);
-        let dsize = unpack_u32(&mmap, 24);
-        let tsize = unpack_u32(&mmap, 28);
-        let _fsize = unpack_u32(&mmap, 32);
-        let _dummy = unpack_u32(&mmap, 36);
+    pub fn open(dic_path: &str, dictype: DicType) -> Result<MeCabDic, Error> {
+        MeCabDic::from_data(dic_path, mmap_file(dic_path)?, dictype)
+    }
+
+    pub fn from_data(dic_path: &str, data: DicData, dictype: DicType) -> Result<MeCabDic, Error> {
+        if data.len() < DIC_HEADER_SIZE {
+            return Err(Error::corrupt(dic_path, "file is too short"));
+        }
+        let size = unpack_u32(&data, 0) ^ DIC_MAGIC_ID;
+        let version = unpack_u32(&data, 4);
+        let file_dictype = unpack_u32(&data, 8);
+        let lexsize = unpack_u32(&data, 12) as usize;
+        let lsize = unpack_u32(&data, 16);
+        let rsize = unpack_u32(&data, 20);
+        let dsize = unpack_u32(&data, 24) as usize;
+        let tsize = unpack_u32(&data, 28) as usize;
+        let fsize = unpack_u32(&data, 32) as usize;
+        let _dummy = unpack_u32(&data, 36);
+        let charset = unpack_string(dic_path, &data[40..DIC_HEADER_SIZE])?;
+
+        if size as usize != data.len() {
+            return Err(Error::corrupt(
+                dic_path,
+                "invalid magic id or file size mismatch",
+            ));
+        }
+        if version != DIC_VERSION {
+            return Err(Error::VersionMismatch {
+                path: dic_path.to_string(),
+                expected: DIC_VERSION,
+                found: version,
+            });
+        }
+        match DicType::from_u32(file_dictype) {
+            Some(t) if t == dictype => {}
+            Some(t) => {
+                return Err(Error::corrupt(
+                    dic_path,
+                    &format!("dictionary type is {:?}, {:?} is expected", t, dictype),
+                ));
+            }
+            None => {
+                return Err(Error::corrupt(
+                    dic_path,
+                    &format!("unknown dictionary type {}", file_dictype),
+                ));
+            }
+        }
+        // the sizes can overflow on 32-bit targets
+        let total_size = DIC_HEADER_SIZE
+            .checked_add(dsize)
+            .and_then(|size| size.checked_add(tsize))
+            .and_then(|size| size.checked_add(fsize));
+        if total_size.is_none_or(|size| size > data.len()) {
+            return Err(Error::corrupt(
+                dic_path,
+                "double array, token and feature sizes exceed the file size",
+            ));
+        }
+        if !dsize.is_multiple_of(8) || !tsize.is_multiple_of(16) || tsize / 16 != lexsize {
+            return Err(Error::corrupt(
+                dic_path,
+                "double array or token size is inconsistent",
+            ));
+        }
+        // the left ids are less than rsize and the right ids are less than lsize like Matrix
+        let token_offset = DIC_HEADER_SIZE + dsize;
+        for i in 0..lexsize {
+            let offset = token_offset + i * 16;
+            let lc_attr = unpack_u16(&data, offset) as u32;
+            let rc_attr = unpack_u16(&data, offset + 2) as u32;
+            if lc_attr >= rsize || rc_attr >= lsize {
+                return Err(Error::corrupt(
+                    dic_path,
+                    &format!(
+                        "context id {},{} of token {} is out of the context id size {}x{}",
+                        lc_attr, rc_attr, i, lsize, rsize
+                    ),
+                ));
+            }
+        }
 
         let dic = MeCabDic {
-            mmap: mmap,
-            da_offset: 72,
-            token_offset: 72 + dsize,
-            feature_offset: 72 + dsize + tsize,
+            data,
+            info: DictionaryInfo {
+                filename: dic_path.to_string(),
+                charset,
+                size: lexsize as u32,
+                dictype,
+                lsize,
+                rsize,
+                version,
+            },
+            lexsize,
+            da_offset: DIC_HEADER_SIZE,
+            da_size: dsize,
+            token_offset,
+            feature_offset: DIC_HEADER_SIZE + dsize + tsize,
+            feature_size: fsize,
         };
         Ok(dic)
     }
 
+    // check the context id sizes are same as matrix.bin
+    pub fn check_matrix(&self, matrix: &Matrix) -> Result<(), Error> {
+        let info = &self.info;
+        if info.lsize as usize != matrix.lsize || info.rsize as usize != matrix.rsize {
+            return Err(Error::corrupt(
+                &info.filename,
+                &format!(
+                    "context id size {}x{} does not match matrix.bin {}x{}",
+                    info.lsize, info.rsize, matrix.lsize, matrix.rsize
+                ),
+            ));
+        }
+        Ok(())
+    }
+
     fn base_check(&self, idx: u32) -> (i32, u32) {
-        let i: usize = (self.da_offset + idx * 8) as usize;
-        (unpack_i32(&self.mmap, i), unpack_u32(&self.mmap, i + 4))
+        // out of the double array never matches
+        if idx as usize >= self.da_size / 8 {
+            return (0, u32::MAX);
+        }
+        let i: usize = self.da_offset + idx as usize * 8;
+        (unpack_i32(&self.data, i), unpack_u32(&self.data, i + 4))
     }
 
     pub fn exact_match_search(&self, s: &[u8]) -> i32 {
@@ -283,8 +516,8 @@ impl MeCabDic {
         let mut p: u32;
 
         let (mut b, _) = self.base_check(0);
-        for (_i, &item) in s.iter().enumerate() {
-            p = (b + (item as i32)) as u32 + 1;
+        for &item in s.iter() {
+            p = b.wrapping_add(item as i32) as u32 + 1;
             let (base, check) = self.base_check(p);
             if b == (check as i32) {
                 b = base;
@@ -301,8 +534,8 @@ impl MeCabDic {
         v
     }
 
-    pub fn common_prefix_search(&self, s: &[u8]) -> Vec<(i32, usize)> {
-        let mut results: Vec<(i32, usize)> = Vec::new();
+    // call f with the value and the length of each prefix of s in the double array
+    pub fn common_prefix_search(&self, s: &[u8], mut f: impl FnMut(i32, usize)) {
         let mut p: u32;
 
         let (mut b, _) = self.base_check(0);
@@ -310,24 +543,22 @@ impl MeCabDic {
             p = b as u32;
             let (n, check) = self.base_check(p);
             if b == (check as i32) && n < 0 {
-                results.push((-n - 1, i as usize));
+                f(-n - 1, i);
             }
-            p = (b + (item as i32)) as u32 + 1;
+            p = b.wrapping_add(item as i32) as u32 + 1;
             let (base, check) = self.base_check(p);
             if b == (check as i32) {
                 b = base;
             } else {
-                return results;
+                return;
             }
         }
         p = b as u32;
 
         let (n, check) = self.base_check(p);
         if b == (check as i32) && n < 0 {
-            results.push((-n - 1, s.len() as usize));
+            f(-n - 1, s.len());
         }
-
-        results
     }
 
     fn get_entries_by_index(
@@ -337,115 +568,324 @@ impl MeCabDic {
         s: &[u8],
         s_len: usize,
         skip: bool,
-    ) -> Vec<DicEntry> {
-        let mut results: Vec<DicEntry> = Vec::new();
+        results: &mut Vec<DicEntry>,
+    ) {
+        let idx = idx as usize;
+        let count = (count as usize).min(self.lexsize.saturating_sub(idx));
+        let features = &self.data[self.feature_offset..self.feature_offset + self.feature_size];
         for i in 0..count {
-            let offset: usize = (self.token_offset + (idx + i) * 16) as usize;
-            let lc_attr = unpack_u16(&self.mmap, offset);
-            let rc_attr = unpack_u16(&self.mmap, offset + 2);
-            let posid = unpack_u16(&self.mmap, offset + 4);
-            let wcost = unpack_i16(&self.mmap, offset + 6);
-
-            let feature = unpack_u32(&self.mmap, offset + 8);
-            let start = (self.feature_offset + feature) as usize;
-            let mut end = start;
-            while self.mmap[end] != 0 {
-                end += 1;
+            let offset: usize = self.token_offset + (idx + i) * 16;
+            let lc_attr = unpack_u16(&self.data, offset);
+            let rc_attr = unpack_u16(&self.data, offset + 2);
+            let posid = unpack_u16(&self.data, offset + 4);
+            let wcost = unpack_i16(&self.data, offset + 6);
+
+            let start = unpack_u32(&self.data, offset + 8) as usize;
+            if start >= features.len() {
+                continue;
             }
+            let feature_len = features[start..]
+                .iter()
+                .position(|&b| b == 0)
+                .unwrap_or(features.len() - start);
 
             results.push(DicEntry {
                 original_ptr: s.as_ptr(),
                 original_len: s_len,
-                lc_attr: lc_attr,
-                rc_attr: rc_attr,
-                posid: posid,
-                wcost: wcost,
-                feature_ptr: (&self.mmap[start..]).as_ptr(),
-                feature_len: end - start,
-                skip: skip,
+                lc_attr,
+                rc_attr,
+                posid,
+                wcost,
+                feature_ptr: features[start..].as_ptr(),
+                feature_len,
+                skip,
+                unknown: false,
             });
         }
+    }
 
-        results
+    // context of the words whose features start with the columns of pos
+    pub fn find_context(&self, pos: &str) -> Option<Context> {
+        let pos = pos.as_bytes();
+        let features = &self.data[self.feature_offset..self.feature_offset + self.feature_size];
+        let mut found: Option<Context> = None;
+        for i in 0..self.lexsize {
+            let offset: usize = self.token_offset + i * 16;
+            let start = unpack_u32(&self.data, offset + 8) as usize;
+            if start >= features.len() {
+                continue;
+            }
+            let feature = &features[start..];
+            if !feature.starts_with(pos) || !matches!(feature.get(pos.len()), Some(b',' | 0) | None)
+            {
+                continue;
+            }
+            let wcost = unpack_i16(&self.data, offset + 6);
+            match found.as_mut() {
+                Some(found) => found.2 = found.2.min(wcost),
+                None => {
+                    let feature_len = feature
+                        .iter()
+                        .position(|&b| b == 0)
+                        .unwrap_or(feature.len());
+                    let columns = feature[..feature_len]
+                        .iter()
+                        .filter(|&&b| b == b',')
+                        .count()
+                        + 1;
+                    found = Some((
+                        unpack_u16(&self.data, offset),
+                        unpack_u16(&self.data, offset + 2),
+                        wcost,
+                        columns,
+                    ));
+                }
+            }
+        }
+        found
     }
 
-    fn get_entries(&self, result: u32, s: &[u8], s_len: usize, skip: bool) -> Vec<DicEntry> {
+    fn get_entries(
+        &self,
+        result: u32,
+        s: &[u8],
+        s_len: usize,
+        skip: bool,
+        results: &mut Vec<DicEntry>,
+    ) {
         let index = result >> 8;
         let count = result & 0xFF;
-        self.get_entries_by_index(index, count, s, s_len, skip)
+        self.get_entries_by_index(index, count, s, s_len, skip, results)
     }
 
-    pub fn lookup(&self, s: &[u8]) -> Vec<DicEntry> {
-        let mut results: Vec<DicEntry> = Vec::new();
-        for (result, len) in self.common_prefix_search(s).iter() {
-            let index = (*result >> 8) as u32;
-            let count = (result & 0xFF) as u32;
-            let mut new_results = self.get_entries_by_index(index, count, s, *len, false);
-            results.append(&mut new_results);
-        }
-        results
+    // append the words which are prefixes of s to results
+    pub fn lookup_into(&self, s: &[u8], results: &mut Vec<DicEntry>) {
+        self.common_prefix_search(s, |result, len| {
+            self.get_entries(result as u32, s, len, false, results);
+        });
     }
 
-    pub fn lookup_unknowns(&self, s: &[u8], cp: &CharProperty) -> (Vec<DicEntry>, bool) {
-        let (default_type, ln_vec, invoke) = cp.get_unknown_lengths(s);
-        let category_name = cp.category_names[default_type as usize].as_bytes();
+    // append the unknown words at the start of s to results,
+    // and return the invoke always flag of the char category
+    pub fn lookup_unknowns_into(
+        &self,
+        s: &[u8],
+        cp: &CharProperty,
+        results: &mut Vec<DicEntry>,
+    ) -> bool {
+        let (default_type, invoke) = cp.get_unknown_type(s);
+        let category_name = match cp.category_names.get(default_type as usize) {
+            Some(name) => name.as_bytes(),
+            None => return invoke,
+        };
         let result = self.exact_match_search(category_name);
-        let mut results: Vec<DicEntry> = Vec::new();
-        for i in ln_vec {
-            let mut new_results = self.get_entries(result as u32, s, i, category_name == b"SPACE");
-            results.append(&mut new_results);
+        if result < 0 {
+            return invoke;
+        }
+        let start = results.len();
+        cp.get_unknown_lengths(s, |len| {
+            self.get_entries(result as u32, s, len, category_name == b"SPACE", results);
+        });
+        for entry in results[start..].iter_mut() {
+            entry.unknown = true;
         }
-        (results, invoke)
+        invoke
     }
 }
 
 #[derive(Clone)]
 pub struct Matrix {
-    mmap: Arc<Mmap>,
+    data: DicData,
     lsize: usize,
-    //    pub rsize: usize,
+    rsize: usize,
 }
 
 impl Matrix {
-    pub fn open(dic_path: &str) -> Result<Matrix, std::io::Error> {
-        let file = File::open(dic_path)?;
-        let mmap = unsafe { Arc::new(MmapOptions::new().map(&file)?) };
-        let lsize = unpack_u16(&mmap, 0) as usize;
-        let _rsize = unpack_u16(&mmap, 2) as usize;
-
-        let matrix = Matrix {
-            mmap: mmap,
-            lsize: lsize,
-            //            rsize: rsize,
-        };
+    pub fn open(dic_path: &str) -> Result<Matrix, Error> {
+        Matrix::from_data(dic_path, mmap_file(dic_path)?)
+    }
+
+    pub fn from_data(dic_path: &str, data: DicData) -> Result<Matrix, Error> {
+        if data.len() < 4 {
+            return Err(Error::corrupt(dic_path, "file is too short"));
+        }
+        let lsize = unpack_u16(&data, 0) as usize;
+        let rsize = unpack_u16(&data, 2) as usize;
+        if (data.len() as u64) < 4 + (lsize * rsize) as u64 * 2 {
+            return Err(Error::corrupt(dic_path, "file is too short"));
+        }
+
+        let matrix = Matrix { data, lsize, rsize };
         Ok(matrix)
     }
 
+    // the left id and the right id of a word are in the matrix
+    pub fn contains(&self, left_id: u16, right_id: u16) -> bool {
+        (left_id as usize) < self.rsize && (right_id as usize) < self.lsize
+    }
+
     pub fn get_trans_cost(&self, id1: u16, id2: u16) -> i32 {
         let id1 = id1 as usize;
         let id2 = id2 as usize;
 
-        unpack_i16(&self.mmap, ((id2 * self.lsize + id1) * 2 + 4) as usize) as i32
+        unpack_i16(&self.data, (id2 * self.lsize + id1) * 2 + 4) as i32
     }
 }
 
+#[cfg(test)]
+use super::tests::{assert_offsets, fixture_dicdir, fixture_tokenizer};
+
 #[test]
 fn test_dic_open() {
     assert!(
-        MeCabDic::open("/something/wrong/path/sys.dic").is_err(),
+        MeCabDic::open("/something/wrong/path/sys.dic", DicType::Sys).is_err(),
         "Error not occured."
     );
 
     let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
-    let result = MeCabDic::open(&mecabrc::get_dic_path(&rc_map, "sys.dic"));
-    assert!(!result.is_err(), "Can't open dict file.");
-    let _sys_dic = result.unwrap();
+    let sys_dic_path = mecabrc::get_dic_path(&rc_map, "sys.dic").unwrap();
+    let result = MeCabDic::open(&sys_dic_path, DicType::Sys);
+    assert!(result.is_ok(), "Can't open dict file.");
+    let sys_dic = result.unwrap();
+    assert_eq!(sys_dic.info.filename, sys_dic_path);
+    assert_eq!(sys_dic.info.dictype, DicType::Sys);
+    assert_eq!(sys_dic.info.version, 102);
+    assert_eq!(sys_dic.info.charset.to_lowercase().replace('-', ""), "utf8");
+    assert!(sys_dic.info.size > 0);
+}
+
+#[test]
+fn test_open_broken_file() {
+    let path = std::env::temp_dir().join(format!("awabi-broken-{}.dic", std::process::id()));
+    std::fs::write(&path, b"broken").unwrap();
+    let path = path.to_str().unwrap();
+
+    assert!(matches!(
+        MeCabDic::open(path, DicType::Sys),
+        Err(Error::CorruptDictionary { .. })
+    ));
+    assert!(matches!(
+        CharProperty::open(path),
+        Err(Error::CorruptDictionary { .. })
+    ));
+    assert!(matches!(
+        Matrix::open(path),
+        Err(Error::CorruptDictionary { .. })
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_dic_header_validation() {
+    let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
+    let sys_dic_path = mecabrc::get_dic_path(&rc_map, "sys.dic").unwrap();
+    let bytes = std::fs::read(&sys_dic_path).unwrap();
+    let path = std::env::temp_dir().join(format!("awabi-header-{}.dic", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    // wrong dictionary type
+    assert!(matches!(
+        MeCabDic::open(&sys_dic_path, DicType::Unk),
+        Err(Error::CorruptDictionary { .. })
+    ));
+
+    // truncated
+    std::fs::write(path, &bytes[..bytes.len() - 1]).unwrap();
+    assert!(matches!(
+        MeCabDic::open(path, DicType::Sys),
+        Err(Error::CorruptDictionary { .. })
+    ));
+
+    // version
+    let mut broken = bytes.clone();
+    broken[4] = 0xff;
+    std::fs::write(path, &broken).unwrap();
+    assert!(matches!(
+        MeCabDic::open(path, DicType::Sys),
+        Err(Error::VersionMismatch { found: 0xff, .. })
+    ));
+
+    // token size
+    let mut broken = bytes.clone();
+    broken[31] = 0xff;
+    std::fs::write(path, &broken).unwrap();
+    assert!(matches!(
+        MeCabDic::open(path, DicType::Sys),
+        Err(Error::CorruptDictionary { .. })
+    ));
+
+    // matrix size
+    let sys_dic = MeCabDic::open(&sys_dic_path, DicType::Sys).unwrap();
+    let matrix = Matrix::open(&mecabrc::get_dic_path(&rc_map, "matrix.bin").unwrap()).unwrap();
+    assert!(sys_dic.check_matrix(&matrix).is_ok());
+    let mut broken = bytes.clone();
+    broken[16] ^= 0x01;
+    std::fs::write(path, &broken).unwrap();
+    let broken_dic = MeCabDic::open(path, DicType::Sys).unwrap();
+    assert!(broken_dic.check_matrix(&matrix).is_err());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_dic_context_ids() {
+    let dicdir = fixture_dicdir();
+    let path = std::env::temp_dir().join(format!("awabi-context-ids-{}.dic", std::process::id()));
+    let path = path.to_str().unwrap();
+    for (name, dictype) in [("sys.dic", DicType::Sys), ("unk.dic", DicType::Unk)] {
+        let bytes = std::fs::read(dicdir.join(name)).unwrap();
+        let dic = MeCabDic::from_data(name, bytes.clone().into(), dictype).unwrap();
+        let token_offset = DIC_HEADER_SIZE + unpack_u32(&bytes, 24) as usize;
+
+        // the left id and the right id of the last token are out of matrix.bin
+        for (id_offset, id) in [(0, dic.info.rsize), (2, dic.info.lsize), (0, 0xffff)] {
+            let mut broken = bytes.clone();
+            let offset = token_offset + (dic.info.size as usize - 1) * 16 + id_offset;
+            broken[offset..offset + 2].copy_from_slice(&(id as u16).to_le_bytes());
+            std::fs::write(path, &broken).unwrap();
+            assert!(matches!(
+                MeCabDic::open(path, dictype),
+                Err(Error::CorruptDictionary { reason, .. }) if reason.starts_with("context id ")
+            ));
+        }
+    }
+
+    // the sizes which overflow on 32-bit targets
+    let mut broken = std::fs::read(dicdir.join("sys.dic")).unwrap();
+    broken[24..36].fill(0xff);
+    assert!(matches!(
+        MeCabDic::from_data("sys.dic", broken.into(), DicType::Sys),
+        Err(Error::CorruptDictionary { .. })
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_utf8_to_ucs2() {
+    let s = "aé漢\u{ffff}😀\u{20b9f}\u{e0100}".as_bytes();
+    assert_eq!(utf8_to_ucs2(s, 0), (0x61, 1));
+    assert_eq!(utf8_to_ucs2(s, 1), (0xe9, 2));
+    assert_eq!(utf8_to_ucs2(s, 3), (0x6f22, 3));
+    // out of char.bin
+    assert_eq!(utf8_to_ucs2(s, 6), (0, 3));
+    // emoji, CJK Extension B and variation selector
+    assert_eq!(utf8_to_ucs2(s, 9), (0, 4));
+    assert_eq!(utf8_to_ucs2(s, 13), (0, 4));
+    assert_eq!(utf8_to_ucs2(s, 17), (0, 4));
+
+    // invalid bytes are 1 byte
+    assert_eq!(utf8_to_ucs2(b"\x80a", 0), (0, 1));
+    assert_eq!(utf8_to_ucs2(b"\xffa", 0), (0, 1));
+    assert_eq!(utf8_to_ucs2(b"\xe6\xbca", 0), (0, 1));
+    assert_eq!(utf8_to_ucs2(b"\xf0\x9f\x98", 0), (0, 1));
+    assert_eq!(utf8_to_ucs2(b"\xe6", 0), (0, 1));
 }
 
 #[test]
 fn test_char_property() {
     let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
-    let cp = CharProperty::open(&mecabrc::get_dic_path(&rc_map, "char.bin")).unwrap();
+    let cp = CharProperty::open(&mecabrc::get_dic_path(&rc_map, "char.bin").unwrap()).unwrap();
 
     assert_eq!(
         cp.category_names,
@@ -478,7 +918,7 @@ fn test_char_property() {
 #[test]
 fn test_get_trans_cost() {
     let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
-    let matrix = Matrix::open(&mecabrc::get_dic_path(&rc_map, "matrix.bin")).unwrap();
+    let matrix = Matrix::open(&mecabrc::get_dic_path(&rc_map, "matrix.bin").unwrap()).unwrap();
     assert_eq!(matrix.get_trans_cost(555, 1283), 340);
     assert_eq!(matrix.get_trans_cost(10, 1293), -1376);
 }
@@ -494,17 +934,23 @@ fn assert_entry(e: &DicEntry, lc_attr: u16, rc_attr: u16, posid: u16, wcost: i16
 #[test]
 fn test_lookup() {
     let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
-    let sys_dic = MeCabDic::open(&mecabrc::get_dic_path(&rc_map, "sys.dic")).unwrap();
+    let sys_dic = MeCabDic::open(
+        &mecabrc::get_dic_path(&rc_map, "sys.dic").unwrap(),
+        DicType::Sys,
+    )
+    .unwrap();
     let sb = "すもももももももものうち".as_bytes();
 
-    let r = sys_dic.common_prefix_search(&sb[0..]);
+    let mut r: Vec<(i32, usize)> = Vec::new();
+    sys_dic.common_prefix_search(&sb[0..], |result, len| r.push((result, len)));
     assert_eq!(r.len(), 3);
     // Ubuntu 18.04's default sys.dic
     //    assert_eq!(r[0], (8849415, 3));
     //    assert_eq!(r[1], (9258497, 6));
     //    assert_eq!(r[2], (9259009, 9));
 
-    let entries = sys_dic.lookup(sb);
+    let mut entries: Vec<DicEntry> = Vec::new();
+    sys_dic.lookup_into(sb, &mut entries);
     assert_eq!(entries.len(), 9);
     // Ubuntu 18.04's default sys.dic
     //    assert_entry(&entries[0], 560, 560, 30, 10247);
@@ -521,13 +967,66 @@ fn test_lookup() {
 #[test]
 fn test_lookup_unknowns() {
     let rc_map = mecabrc::rc_map(&mecabrc::find_mecabrc().unwrap()).unwrap();
-    let unk_dic = MeCabDic::open(&mecabrc::get_dic_path(&rc_map, "unk.dic")).unwrap();
-    let cp = CharProperty::open(&mecabrc::get_dic_path(&rc_map, "char.bin")).unwrap();
+    let unk_dic = MeCabDic::open(
+        &mecabrc::get_dic_path(&rc_map, "unk.dic").unwrap(),
+        DicType::Unk,
+    )
+    .unwrap();
+    let cp = CharProperty::open(&mecabrc::get_dic_path(&rc_map, "char.bin").unwrap()).unwrap();
 
     assert_eq!(unk_dic.exact_match_search(b"SPACE"), 9729);
 
-    let (entries, invoke) = unk_dic.lookup_unknowns("１９６７年".as_bytes(), &cp);
+    let mut entries: Vec<DicEntry> = Vec::new();
+    let invoke = unk_dic.lookup_unknowns_into("１９６７年".as_bytes(), &cp, &mut entries);
     assert_eq!(entries.len(), 1);
-    assert_eq!(invoke, true);
+    assert!(invoke);
     assert_eq!(entries[0].original_string(), "１９６７".to_string())
 }
+
+#[test]
+fn test_non_bmp_chars() {
+    let tokenizer = fixture_tokenizer();
+    let parse = |s: &str| {
+        let tokens = tokenizer.parse(s);
+        assert_offsets(s, &tokens);
+        tokens
+            .into_iter()
+            .map(|t| (t.surface, t.feature))
+            .collect::<Vec<_>>()
+    };
+    let default = "記号,一般,*,*,*,*,*".to_string();
+    let tokyo = "名詞,固有名詞,地域,一般,*,*,東京,トウキョウ,トーキョー".to_string();
+
+    // emoji
+    assert_eq!(
+        parse("😀😀東京"),
+        vec![
+            ("😀😀".to_string(), default.clone()),
+            ("東京".to_string(), tokyo.clone())
+        ]
+    );
+    // CJK Extension B, U+24741 was HIRAGANA by the broken surrogate
+    assert_eq!(
+        parse("𤝁東京"),
+        vec![
+            ("𤝁".to_string(), default.clone()),
+            ("東京".to_string(), tokyo.clone())
+        ]
+    );
+    assert_eq!(parse("𠮷")[0], ("𠮷".to_string(), default.clone()));
+    // variation selectors
+    assert_eq!(
+        parse("東京\u{fe0f}\u{e0100}"),
+        vec![
+            ("東京".to_string(), tokyo.clone()),
+            ("\u{fe0f}\u{e0100}".to_string(), default.clone())
+        ]
+    );
+    assert_eq!(
+        parse("葛\u{e0100}東京")
+            .into_iter()
+            .map(|(surface, _)| surface)
+            .collect::<Vec<_>>(),
+        vec!["葛", "\u{e0100}", "東京"]
+    );
+}
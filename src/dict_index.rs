@@ -0,0 +1,983 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+//! Dictionary compiler like `mecab-dict-index`.
+//!
+//! It reads a source dictionary directory (`*.csv`, `matrix.def`, `char.def`,
+//! `unk.def`, `dicrc` and optional `pos-id.def`) and writes `sys.dic`,
+//! `unk.dic`, `char.bin` and `matrix.bin`.
+//! Source files must be UTF-8.
+//!
+//! Left and right context ids in csv files may be empty when the dictionary
+//! directory has `rewrite.def`, `left-id.def` and `right-id.def`.
+//! They are assigned from the features like `mecab-dict-index -u`.
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::Error;
+use super::csv::split_csv;
+use super::dic::DicType;
+use super::double_array;
+use super::mecabrc;
+
+const DIC_MAGIC_ID: u32 = 0xef718f77;
+const DIC_VERSION: u32 = 102;
+const CHAR_TABLE_SIZE: usize = 0xffff;
+
+// a word of the source csv
+#[derive(Debug, Clone)]
+struct Entry {
+    surface: String,
+    left_id: u16,
+    right_id: u16,
+    posid: u16,
+    cost: i16,
+    feature: String,
+    // csv file and 1-based line number for the errors
+    path: Rc<Path>,
+    line: usize,
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn read_text(path: &Path) -> Result<String, Error> {
+    let bytes = fs::read(path).map_err(|e| Error::io(&path_string(path), e))?;
+    String::from_utf8(bytes).map_err(|_| Error::InvalidUtf8 {
+        path: path_string(path),
+    })
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    fs::write(path, bytes).map_err(|e| Error::io(&path_string(path), e))
+}
+
+fn format_error(path: &Path, line: usize, reason: &str) -> Error {
+    Error::InvalidFormat {
+        path: path_string(path),
+        line,
+        reason: reason.to_string(),
+    }
+}
+
+fn parse_int<T: std::str::FromStr>(
+    path: &Path,
+    line: usize,
+    s: &str,
+    name: &str,
+) -> Result<T, Error> {
+    s.trim()
+        .parse::<T>()
+        .map_err(|_| format_error(path, line, &format!("invalid {}: {}", name, s)))
+}
+
+// a pattern of features like "名詞,(一般|固有名詞),*"
+#[derive(Debug, Clone)]
+pub(crate) struct FeaturePattern {
+    columns: Vec<String>,
+}
+
+impl FeaturePattern {
+    pub(crate) fn new(pattern: &str) -> FeaturePattern {
+        FeaturePattern {
+            columns: split_csv(pattern, usize::MAX),
+        }
+    }
+
+    fn match_column(pattern: &str, s: &str) -> bool {
+        if pattern == "*" || pattern == s {
+            return true;
+        }
+        if pattern.len() >= 3 && pattern.starts_with('(') && pattern.ends_with(')') {
+            return pattern[1..pattern.len() - 1].split('|').any(|p| p == s);
+        }
+        false
+    }
+
+    pub(crate) fn is_match(&self, features: &[String]) -> bool {
+        self.columns.len() <= features.len()
+            && self
+                .columns
+                .iter()
+                .zip(features.iter())
+                .all(|(p, s)| FeaturePattern::match_column(p, s))
+    }
+}
+
+// pos-id.def
+struct PosIdGenerator {
+    rules: Vec<(FeaturePattern, u16)>,
+}
+
+impl PosIdGenerator {
+    fn open(path: &Path) -> Result<PosIdGenerator, Error> {
+        let mut rules: Vec<(FeaturePattern, u16)> = Vec::new();
+        if !path.exists() {
+            return Ok(PosIdGenerator { rules });
+        }
+        for (i, line) in read_text(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (pattern, id) = line
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| format_error(path, i + 1, "format error"))?;
+            rules.push((
+                FeaturePattern::new(pattern.trim()),
+                parse_int(path, i + 1, id, "pos id")?,
+            ));
+        }
+        Ok(PosIdGenerator { rules })
+    }
+
+    fn id(&self, feature: &str) -> u16 {
+        let features = split_csv(feature, usize::MAX);
+        for (pattern, id) in self.rules.iter() {
+            if pattern.is_match(&features) {
+                return *id;
+            }
+        }
+        0
+    }
+}
+
+// a rule of rewrite.def like "名詞,*,*,*,*,*,* $1,$2,$3,$4,$5,$6,*"
+struct RewriteRule {
+    pattern: FeaturePattern,
+    output: String,
+}
+
+impl RewriteRule {
+    fn rewrite(&self, features: &[String]) -> Option<String> {
+        if !self.pattern.is_match(features) {
+            return None;
+        }
+        let mut s = String::new();
+        let mut chars = self.output.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                s.push(c);
+                continue;
+            }
+            let mut n: usize = 0;
+            while let Some(d) = chars.peek().and_then(|d| d.to_digit(10)) {
+                n = n * 10 + d as usize;
+                chars.next();
+            }
+            if n > 0 && n <= features.len() {
+                s.push_str(&features[n - 1]);
+            }
+        }
+        Some(s)
+    }
+}
+
+// assign context ids from features with rewrite.def, left-id.def and right-id.def
+struct ContextId {
+    left_rules: Vec<RewriteRule>,
+    right_rules: Vec<RewriteRule>,
+    left_ids: HashMap<String, u16>,
+    right_ids: HashMap<String, u16>,
+}
+
+impl ContextId {
+    fn open(dicdir: &Path) -> Result<Option<ContextId>, Error> {
+        let rewrite_path = dicdir.join("rewrite.def");
+        let left_path = dicdir.join("left-id.def");
+        let right_path = dicdir.join("right-id.def");
+        if !rewrite_path.exists() || !left_path.exists() || !right_path.exists() {
+            return Ok(None);
+        }
+
+        let mut left_rules: Vec<RewriteRule> = Vec::new();
+        let mut right_rules: Vec<RewriteRule> = Vec::new();
+        let mut section = "";
+        for (i, line) in read_text(&rewrite_path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = match line {
+                    "[unigram rewrite]" => "unigram",
+                    "[left rewrite]" => "left",
+                    "[right rewrite]" => "right",
+                    _ => return Err(format_error(&rewrite_path, i + 1, "unknown section")),
+                };
+                continue;
+            }
+            let (pattern, output) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format_error(&rewrite_path, i + 1, "format error"))?;
+            let rule = RewriteRule {
+                pattern: FeaturePattern::new(pattern),
+                output: output.trim().to_string(),
+            };
+            match section {
+                "left" => left_rules.push(rule),
+                "right" => right_rules.push(rule),
+                "unigram" => {}
+                _ => return Err(format_error(&rewrite_path, i + 1, "no section")),
+            }
+        }
+
+        Ok(Some(ContextId {
+            left_rules,
+            right_rules,
+            left_ids: ContextId::read_ids(&left_path)?,
+            right_ids: ContextId::read_ids(&right_path)?,
+        }))
+    }
+
+    // "id feature" lines
+    fn read_ids(path: &Path) -> Result<HashMap<String, u16>, Error> {
+        let mut ids: HashMap<String, u16> = HashMap::new();
+        for (i, line) in read_text(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (id, feature) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format_error(path, i + 1, "format error"))?;
+            ids.insert(
+                feature.trim().to_string(),
+                parse_int(path, i + 1, id, "context id")?,
+            );
+        }
+        Ok(ids)
+    }
+
+    fn id(rules: &[RewriteRule], ids: &HashMap<String, u16>, features: &[String]) -> Option<u16> {
+        let feature = rules.iter().find_map(|rule| rule.rewrite(features))?;
+        ids.get(&feature).copied()
+    }
+
+    fn left_id(&self, feature: &str) -> Option<u16> {
+        let features = split_csv(feature, usize::MAX);
+        ContextId::id(&self.left_rules, &self.left_ids, &features)
+    }
+
+    fn right_id(&self, feature: &str) -> Option<u16> {
+        let features = split_csv(feature, usize::MAX);
+        ContextId::id(&self.right_rules, &self.right_ids, &features)
+    }
+}
+
+// read "surface,left_id,right_id,cost,feature" lines,
+// the context ids must be less than the sizes of matrix.def
+fn read_csv(
+    path: &Path,
+    posid: &PosIdGenerator,
+    context_id: Option<&ContextId>,
+    (lsize, rsize): (u16, u16),
+) -> Result<Vec<Entry>, Error> {
+    // empty id is assigned from the feature
+    let get_id = |id: &str, line: usize, left: bool, feature: &str| -> Result<u16, Error> {
+        let name = if left { "left id" } else { "right id" };
+        if !id.trim().is_empty() {
+            return parse_int(path, line, id, name);
+        }
+        let assigned = match context_id {
+            Some(c) if left => c.left_id(feature),
+            Some(c) => c.right_id(feature),
+            None => {
+                return Err(format_error(
+                    path,
+                    line,
+                    "context id is empty and rewrite.def, left-id.def or right-id.def is not found",
+                ));
+            }
+        };
+        assigned.ok_or_else(|| {
+            format_error(path, line, &format!("can't find {} for {}", name, feature))
+        })
+    };
+
+    let shared_path: Rc<Path> = Rc::from(path);
+    let mut entries: Vec<Entry> = Vec::new();
+    for (i, line) in read_text(path)?.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let columns = split_csv(line, 5);
+        if columns.len() != 5 {
+            return Err(format_error(path, i + 1, "format error"));
+        }
+        let cost: i32 = parse_int(path, i + 1, &columns[3], "cost")?;
+        if cost < i16::MIN as i32 || cost > i16::MAX as i32 {
+            return Err(format_error(path, i + 1, "cost is out of range"));
+        }
+        if columns[0].is_empty() {
+            return Err(format_error(path, i + 1, "empty surface"));
+        }
+        let left_id = get_id(&columns[1], i + 1, true, &columns[4])?;
+        let right_id = get_id(&columns[2], i + 1, false, &columns[4])?;
+        if left_id >= rsize || right_id >= lsize {
+            return Err(format_error(
+                path,
+                i + 1,
+                &format!("context id of {} is out of range", columns[0]),
+            ));
+        }
+        entries.push(Entry {
+            surface: columns[0].clone(),
+            left_id,
+            right_id,
+            posid: posid.id(&columns[4]),
+            cost: cost as i16,
+            feature: columns[4].clone(),
+            path: Rc::clone(&shared_path),
+            line: i + 1,
+        });
+    }
+    Ok(entries)
+}
+
+// matrix.def -> matrix.bin
+fn compile_matrix(path: &Path) -> Result<(u16, u16, Vec<u8>), Error> {
+    let text = read_text(path)?;
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    let (lsize, rsize) = match lines.next() {
+        Some((i, line)) => {
+            let v: Vec<&str> = line.split_whitespace().collect();
+            if v.len() != 2 {
+                return Err(format_error(path, i + 1, "format error"));
+            }
+            let lsize: u16 = parse_int(path, i + 1, v[0], "left size")?;
+            let rsize: u16 = parse_int(path, i + 1, v[1], "right size")?;
+            (lsize, rsize)
+        }
+        None => return Err(format_error(path, 1, "matrix size is not found")),
+    };
+
+    let mut costs: Vec<i16> = vec![0; lsize as usize * rsize as usize];
+    for (i, line) in lines {
+        let v: Vec<&str> = line.split_whitespace().collect();
+        if v.len() != 3 {
+            return Err(format_error(path, i + 1, "format error"));
+        }
+        let lid: usize = parse_int(path, i + 1, v[0], "left id")?;
+        let rid: usize = parse_int(path, i + 1, v[1], "right id")?;
+        let cost: i16 = parse_int(path, i + 1, v[2], "cost")?;
+        if lid >= lsize as usize || rid >= rsize as usize {
+            return Err(format_error(path, i + 1, "context id is out of range"));
+        }
+        costs[lid + lsize as usize * rid] = cost;
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(4 + costs.len() * 2);
+    bytes.extend_from_slice(&lsize.to_le_bytes());
+    bytes.extend_from_slice(&rsize.to_le_bytes());
+    for cost in costs.iter() {
+        bytes.extend_from_slice(&cost.to_le_bytes());
+    }
+    Ok((lsize, rsize, bytes))
+}
+
+// char.def -> char.bin, returns category names and char.bin
+fn compile_char_property(path: &Path) -> Result<(Vec<String>, Vec<u8>), Error> {
+    // name -> (id, invoke, group, length)
+    let mut categories: HashMap<String, (u32, u32, u32, u32)> = HashMap::new();
+    let mut names: Vec<String> = Vec::new();
+    let mut ranges: Vec<(u32, u32, Vec<String>, usize)> = Vec::new();
+
+    for (i, line) in read_text(path)?.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let v: Vec<&str> = line.split_whitespace().collect();
+        if v[0].starts_with("0x") {
+            if v.len() < 2 {
+                return Err(format_error(path, i + 1, "format error"));
+            }
+            let (low, high) = v[0].split_once("..").unwrap_or((v[0], v[0]));
+            let parse_code = |s: &str| {
+                u32::from_str_radix(s.trim_start_matches("0x"), 16)
+                    .map_err(|_| format_error(path, i + 1, &format!("invalid code point: {}", s)))
+            };
+            let low = parse_code(low)?;
+            let high = parse_code(high)?;
+            if low > high {
+                return Err(format_error(path, i + 1, "invalid code point range"));
+            }
+            let cats: Vec<String> = v[1..].iter().map(|s| s.to_string()).collect();
+            ranges.push((low, high, cats, i + 1));
+        } else {
+            if v.len() != 4 {
+                return Err(format_error(path, i + 1, "format error"));
+            }
+            let invoke: u32 = parse_int(path, i + 1, v[1], "invoke")?;
+            let group: u32 = parse_int(path, i + 1, v[2], "group")?;
+            let length: u32 = parse_int(path, i + 1, v[3], "length")?;
+            if invoke > 1 || group > 1 {
+                return Err(format_error(path, i + 1, "invalid category definition"));
+            }
+            // the length is stored in 4 bits of char.bin
+            if length > 15 {
+                return Err(format_error(path, i + 1, "length must be less than 16"));
+            }
+            if v[0].len() >= 32 {
+                return Err(format_error(path, i + 1, "category name is too long"));
+            }
+            if categories.contains_key(v[0]) {
+                return Err(format_error(path, i + 1, "category is already defined"));
+            }
+            // the categories of a char are a bitmask of 18 bits in char.bin
+            if names.len() >= 18 {
+                return Err(format_error(path, i + 1, "too many categories"));
+            }
+            categories.insert(
+                v[0].to_string(),
+                (names.len() as u32, invoke, group, length),
+            );
+            names.push(v[0].to_string());
+        }
+    }
+
+    for name in ["DEFAULT", "SPACE"] {
+        if !categories.contains_key(name) {
+            return Err(format_error(
+                path,
+                0,
+                &format!("category {} is not defined", name),
+            ));
+        }
+    }
+
+    let encode = |cats: &[String], line: usize| -> Result<u32, Error> {
+        let mut t: u32 = 0;
+        for c in cats.iter() {
+            match categories.get(c) {
+                Some(&(id, _, _, _)) => t |= 1 << id,
+                None => {
+                    return Err(format_error(
+                        path,
+                        line,
+                        &format!("category {} is not defined", c),
+                    ));
+                }
+            }
+        }
+        let (id, invoke, group, length) = categories[&cats[0]];
+        Ok(t | (id << 18) | (length << 26) | (group << 30) | (invoke << 31))
+    };
+
+    let default = encode(&["DEFAULT".to_string()], 0)?;
+    let mut table: Vec<u32> = vec![default; CHAR_TABLE_SIZE];
+    for (low, high, cats, line) in ranges.iter() {
+        let v = encode(cats, *line)?;
+        for c in *low..=(*high).min(CHAR_TABLE_SIZE as u32 - 1) {
+            table[c as usize] = v;
+        }
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(4 + names.len() * 32 + CHAR_TABLE_SIZE * 4);
+    bytes.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    for name in names.iter() {
+        let mut buf = [0u8; 32];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&buf);
+    }
+    for v in table.iter() {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    Ok((names, bytes))
+}
+
+// make a binary dictionary from entries
+fn compile_dic(
+    mut entries: Vec<Entry>,
+    dictype: DicType,
+    lsize: u16,
+    rsize: u16,
+    charset: &str,
+) -> Result<Vec<u8>, Error> {
+    entries.sort_by(|a, b| a.surface.as_bytes().cmp(b.surface.as_bytes()));
+
+    let mut keys: Vec<(&[u8], u32)> = Vec::new();
+    let mut tokens: Vec<u8> = Vec::with_capacity(entries.len() * 16);
+    let mut features: Vec<u8> = Vec::new();
+    let mut feature_offsets: HashMap<&str, u32> = HashMap::new();
+
+    let mut i = 0;
+    while i < entries.len() {
+        let surface = entries[i].surface.as_bytes();
+        let mut j = i;
+        while j < entries.len() && entries[j].surface.as_bytes() == surface {
+            j += 1;
+        }
+        if j - i > 0xff {
+            return Err(format_error(
+                &entries[j - 1].path,
+                entries[j - 1].line,
+                &format!("too many homographs of {}", entries[i].surface),
+            ));
+        }
+        keys.push((surface, ((i as u32) << 8) | (j - i) as u32));
+
+        for e in entries[i..j].iter() {
+            let feature = *feature_offsets.entry(&e.feature).or_insert_with(|| {
+                let offset = features.len() as u32;
+                features.extend_from_slice(e.feature.as_bytes());
+                features.push(0);
+                offset
+            });
+            tokens.extend_from_slice(&e.left_id.to_le_bytes());
+            tokens.extend_from_slice(&e.right_id.to_le_bytes());
+            tokens.extend_from_slice(&e.posid.to_le_bytes());
+            tokens.extend_from_slice(&e.cost.to_le_bytes());
+            tokens.extend_from_slice(&feature.to_le_bytes());
+            tokens.extend_from_slice(&0u32.to_le_bytes()); // compound
+        }
+        i = j;
+    }
+
+    let da = double_array::build(&keys);
+
+    let size = 72 + da.len() + tokens.len() + features.len();
+    let mut bytes: Vec<u8> = Vec::with_capacity(size);
+    for v in [
+        size as u32 ^ DIC_MAGIC_ID,
+        DIC_VERSION,
+        dictype as u32,
+        entries.len() as u32,
+        lsize as u32,
+        rsize as u32,
+        da.len() as u32,
+        tokens.len() as u32,
+        features.len() as u32,
+        0,
+    ] {
+        bytes.extend_from_slice(&v.to_le_bytes());
+    }
+    let mut buf = [0u8; 32];
+    let n = charset.len().min(31);
+    buf[..n].copy_from_slice(&charset.as_bytes()[..n]);
+    bytes.extend_from_slice(&buf);
+    bytes.extend_from_slice(&da);
+    bytes.extend_from_slice(&tokens);
+    bytes.extend_from_slice(&features);
+    Ok(bytes)
+}
+
+// "config-charset" in dicrc
+fn read_charset(dicdir: &Path) -> Result<String, Error> {
+    let path = dicdir.join("dicrc");
+    if path.exists() {
+        let dicrc = mecabrc::Mecabrc::open(&path_string(&path))?;
+        if let Some(charset) = dicrc.get("config-charset") {
+            return Ok(charset.trim().to_string());
+        }
+    }
+    Ok("UTF-8".to_string())
+}
+
+fn csv_files(dicdir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = fs::read_dir(dicdir).map_err(|e| Error::io(&path_string(dicdir), e))?;
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in dir {
+        let path = entry
+            .map_err(|e| Error::io(&path_string(dicdir), e))?
+            .path();
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compile the source dictionary in `dicdir` and write binary dictionaries to `outdir`.
+pub fn compile(dicdir: &str, outdir: &str) -> Result<(), Error> {
+    let dicdir = Path::new(dicdir);
+    let outdir = Path::new(outdir);
+    fs::create_dir_all(outdir).map_err(|e| Error::io(&path_string(outdir), e))?;
+    let charset = read_charset(dicdir)?;
+    let posid = PosIdGenerator::open(&dicdir.join("pos-id.def"))?;
+    let context_id = ContextId::open(dicdir)?;
+
+    let (lsize, rsize, matrix) = compile_matrix(&dicdir.join("matrix.def"))?;
+    write_file(&outdir.join("matrix.bin"), &matrix)?;
+
+    let (categories, char_property) = compile_char_property(&dicdir.join("char.def"))?;
+    write_file(&outdir.join("char.bin"), &char_property)?;
+
+    let unk_path = dicdir.join("unk.def");
+    let unk_entries = read_csv(&unk_path, &posid, context_id.as_ref(), (lsize, rsize))?;
+    for e in unk_entries.iter() {
+        if !categories.contains(&e.surface) {
+            return Err(format_error(
+                &unk_path,
+                e.line,
+                &format!("category {} is not defined in char.def", e.surface),
+            ));
+        }
+    }
+    let unk_dic = compile_dic(unk_entries, DicType::Unk, lsize, rsize, &charset)?;
+    write_file(&outdir.join("unk.dic"), &unk_dic)?;
+
+    let mut entries: Vec<Entry> = Vec::new();
+    for path in csv_files(dicdir)?.iter() {
+        entries.append(&mut read_csv(
+            path,
+            &posid,
+            context_id.as_ref(),
+            (lsize, rsize),
+        )?);
+    }
+    let sys_dic = compile_dic(entries, DicType::Sys, lsize, rsize, &charset)?;
+    write_file(&outdir.join("sys.dic"), &sys_dic)?;
+
+    // the dictionary directory needs dicrc
+    let dicrc = dicdir.join("dicrc");
+    if dicrc.exists() && !outdir.join("dicrc").exists() {
+        fs::copy(&dicrc, outdir.join("dicrc")).map_err(|e| Error::io(&path_string(&dicrc), e))?;
+    }
+
+    Ok(())
+}
+
+// context id sizes from matrix.bin, or matrix.def
+fn read_matrix_size(dicdir: &Path) -> Result<(u16, u16), Error> {
+    let path = dicdir.join("matrix.bin");
+    if path.exists() {
+        let mut buf = [0u8; 4];
+        let mut f = fs::File::open(&path).map_err(|e| Error::io(&path_string(&path), e))?;
+        f.read_exact(&mut buf)
+            .map_err(|e| Error::io(&path_string(&path), e))?;
+        return Ok((
+            u16::from_le_bytes([buf[0], buf[1]]),
+            u16::from_le_bytes([buf[2], buf[3]]),
+        ));
+    }
+    let path = dicdir.join("matrix.def");
+    let text = read_text(&path)?;
+    let line = text.lines().next().unwrap_or("");
+    let v: Vec<&str> = line.split_whitespace().collect();
+    if v.len() != 2 {
+        return Err(format_error(&path, 1, "format error"));
+    }
+    Ok((
+        parse_int(&path, 1, v[0], "left size")?,
+        parse_int(&path, 1, v[1], "right size")?,
+    ))
+}
+
+/// Compile user dictionary csv files to `out_path` for the system dictionary in `dicdir`.
+///
+/// `dicdir` is the system dictionary directory which has `matrix.bin` or `matrix.def`.
+/// Empty context ids in csv files are assigned with `rewrite.def`, `left-id.def`
+/// and `right-id.def` in `dicdir`.
+pub fn compile_user_dic(dicdir: &str, csv_paths: &[&str], out_path: &str) -> Result<(), Error> {
+    let dicdir = Path::new(dicdir);
+    let out_path = Path::new(out_path);
+    let charset = read_charset(dicdir)?;
+    let posid = PosIdGenerator::open(&dicdir.join("pos-id.def"))?;
+    let context_id = ContextId::open(dicdir)?;
+    let (lsize, rsize) = read_matrix_size(dicdir)?;
+
+    let mut entries: Vec<Entry> = Vec::new();
+    for path in csv_paths.iter() {
+        entries.append(&mut read_csv(
+            Path::new(path),
+            &posid,
+            context_id.as_ref(),
+            (lsize, rsize),
+        )?);
+    }
+    let user_dic = compile_dic(entries, DicType::Usr, lsize, rsize, &charset)?;
+    write_file(out_path, &user_dic)
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("awabi-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+// entries of the words which are prefixes of s
+#[cfg(test)]
+fn lookup(dic: &super::dic::MeCabDic, s: &[u8]) -> Vec<super::dic::DicEntry> {
+    let mut entries = Vec::new();
+    dic.lookup_into(s, &mut entries);
+    entries
+}
+
+#[test]
+fn test_feature_pattern() {
+    let pattern = FeaturePattern::new("名詞,(一般|固有名詞),*");
+    assert!(pattern.is_match(&split_csv("名詞,一般,*,*", usize::MAX)));
+    assert!(pattern.is_match(&split_csv("名詞,固有名詞,人名", usize::MAX)));
+    assert!(!pattern.is_match(&split_csv("名詞,数,*", usize::MAX)));
+    assert!(!pattern.is_match(&split_csv("名詞,一般", usize::MAX)));
+}
+
+#[test]
+fn test_double_array() {
+    use super::dic::MeCabDic;
+
+    let csv_path: Rc<Path> = Rc::from(Path::new("test.csv"));
+    let mut entries: Vec<Entry> = Vec::new();
+    for i in 0..3000 {
+        let surface = format!("{}語{}", i % 7, i);
+        for j in 0..(i % 3 + 1) {
+            entries.push(Entry {
+                surface: surface.clone(),
+                left_id: 0,
+                right_id: 0,
+                posid: 0,
+                cost: j as i16,
+                feature: format!("feature{}", i),
+                path: Rc::clone(&csv_path),
+                line: entries.len() + 1,
+            });
+        }
+    }
+    let dir = test_dir("double-array");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sys.dic");
+    let bytes = compile_dic(entries, DicType::Sys, 1, 1, "UTF-8").unwrap();
+    write_file(&path, &bytes).unwrap();
+
+    let dic = MeCabDic::open(path.to_str().unwrap(), DicType::Sys).unwrap();
+    assert_eq!(dic.info.size, 6000);
+    for i in 0..3000 {
+        let surface = format!("{}語{}", i % 7, i);
+        let entries = lookup(&dic, surface.as_bytes());
+        let found: Vec<_> = entries
+            .iter()
+            .filter(|e| e.original_len == surface.len())
+            .collect();
+        assert_eq!(found.len(), i % 3 + 1);
+        assert!(dic.exact_match_search(surface.as_bytes()) >= 0);
+    }
+    assert_eq!(dic.exact_match_search("0語".as_bytes()), -1);
+    assert_eq!(dic.exact_match_search("9語1".as_bytes()), -1);
+    // "1語1", "1語15", "1語155" and "1語1555"
+    let mut count = 0;
+    dic.common_prefix_search("1語15559".as_bytes(), |_, _| count += 1);
+    assert_eq!(count, 4);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_compile() {
+    use super::dic::{CharProperty, Matrix, MeCabDic};
+    use super::tokenizer::Tokenizer;
+
+    let dicdir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dic");
+    let outdir = test_dir("compile");
+    compile(dicdir.to_str().unwrap(), outdir.to_str().unwrap()).unwrap();
+
+    let matrix = Matrix::open(outdir.join("matrix.bin").to_str().unwrap()).unwrap();
+    assert_eq!(matrix.get_trans_cost(1, 2), -1500);
+    assert_eq!(matrix.get_trans_cost(2, 1), -1000);
+
+    let cp = CharProperty::open(outdir.join("char.bin").to_str().unwrap()).unwrap();
+    assert_eq!(cp.category_names[..3], ["DEFAULT", "SPACE", "KANJI"]);
+    assert_eq!(cp.get_char_info(0x3007), (3, 264, 0, 1, 1)); // SYMBOL KANJINUMERIC
+    assert_eq!(cp.get_char_info(0x3042), (6, 64, 2, 1, 0)); // HIRAGANA
+
+    let unk_dic = MeCabDic::open(outdir.join("unk.dic").to_str().unwrap(), DicType::Unk).unwrap();
+    assert_eq!(unk_dic.info.size, 11);
+    let sys_dic = MeCabDic::open(outdir.join("sys.dic").to_str().unwrap(), DicType::Sys).unwrap();
+    assert_eq!(sys_dic.info.charset, "UTF-8");
+    assert_eq!((sys_dic.info.lsize, sys_dic.info.rsize), (7, 7));
+    let entries = lookup(&sys_dic, "すもも".as_bytes());
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].lc_attr, entries[0].rc_attr), (1, 1));
+    assert_eq!((entries[0].posid, entries[0].wcost), (38, 7000));
+    assert_eq!(lookup(&sys_dic, "東京".as_bytes())[1].posid, 40);
+
+    let rcfile = outdir.join("mecabrc");
+    write_file(
+        &rcfile,
+        format!("dicdir = {}\n", outdir.to_str().unwrap()).as_bytes(),
+    )
+    .unwrap();
+    let tokenizer = Tokenizer::new(rcfile.to_str()).unwrap();
+    assert_eq!(
+        tokenizer
+            .parse("すもももももももものうち")
+            .iter()
+            .map(|t| t.surface.as_str())
+            .collect::<Vec<_>>(),
+        vec!["すもも", "も", "もも", "も", "もも", "の", "うち"]
+    );
+
+    fs::remove_dir_all(&outdir).unwrap();
+}
+
+#[test]
+fn test_compile_user_dic() {
+    use super::dic::MeCabDic;
+    use super::tokenizer::Tokenizer;
+
+    let dicdir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dic");
+    let outdir = test_dir("user-dic");
+    compile(dicdir.to_str().unwrap(), outdir.to_str().unwrap()).unwrap();
+
+    let csv_path = outdir.join("user.csv");
+    write_file(
+        &csv_path,
+        "すももも,,,3000,名詞,固有名詞,人名,一般,*,*,すももも,スモモモ,スモモモ\n\
+         あわび,1,1,1000,名詞,固有名詞,組織,*,*,*,あわび,アワビ,アワビ\n\
+         ですも,,,1000,助動詞,*,*,*,特殊・デス,基本形,ですも,デスモ,デスモ\n"
+            .as_bytes(),
+    )
+    .unwrap();
+    let user_dic_path = outdir.join("user.dic");
+    compile_user_dic(
+        dicdir.to_str().unwrap(),
+        &[csv_path.to_str().unwrap()],
+        user_dic_path.to_str().unwrap(),
+    )
+    .unwrap();
+
+    let user_dic = MeCabDic::open(user_dic_path.to_str().unwrap(), DicType::Usr).unwrap();
+    assert_eq!(user_dic.info.size, 3);
+    assert_eq!((user_dic.info.lsize, user_dic.info.rsize), (7, 7));
+    let entries = lookup(&user_dic, "すももも".as_bytes());
+    assert_eq!((entries[0].lc_attr, entries[0].rc_attr), (1, 1));
+    assert_eq!(entries[0].posid, 40);
+    let entries = lookup(&user_dic, "ですも".as_bytes());
+    assert_eq!((entries[0].lc_attr, entries[0].rc_attr), (4, 4));
+
+    // the user dictionary for binary system dictionary
+    fs::copy(dicdir.join("left-id.def"), outdir.join("left-id.def")).unwrap();
+    fs::copy(dicdir.join("right-id.def"), outdir.join("right-id.def")).unwrap();
+    fs::copy(dicdir.join("rewrite.def"), outdir.join("rewrite.def")).unwrap();
+    compile_user_dic(
+        outdir.to_str().unwrap(),
+        &[csv_path.to_str().unwrap()],
+        user_dic_path.to_str().unwrap(),
+    )
+    .unwrap();
+
+    let rcfile = outdir.join("mecabrc");
+    write_file(
+        &rcfile,
+        format!(
+            "dicdir = {}\nuserdic = {}\n",
+            outdir.to_str().unwrap(),
+            user_dic_path.to_str().unwrap()
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+    let tokenizer = Tokenizer::new(rcfile.to_str()).unwrap();
+    let tokens = tokenizer.parse("すもももあわび");
+    assert_eq!(tokens[0].surface, "すももも");
+    assert_eq!(tokens[1].surface, "あわび");
+
+    // no left id
+    write_file(&csv_path, "うに,,,1000,未知語,*,*,*,*,*,*\n".as_bytes()).unwrap();
+    assert!(matches!(
+        compile_user_dic(
+            dicdir.to_str().unwrap(),
+            &[csv_path.to_str().unwrap()],
+            user_dic_path.to_str().unwrap(),
+        ),
+        Err(Error::InvalidFormat { line: 1, .. })
+    ));
+
+    // the csv file and the line of the error
+    write_file(
+        &csv_path,
+        "あわび,1,1,1000,名詞,一般,*,*,*,*,*\n".as_bytes(),
+    )
+    .unwrap();
+    let csv_path2 = outdir.join("user2.csv");
+    let csv2 = csv_path2.to_str().unwrap().to_string();
+    // context id out of range and empty surface
+    for csv in [
+        "いくら,1,7,1000,名詞,一般,*,*,*,*,*\n",
+        ",1,1,1000,名詞,一般,*,*,*,*,*\n",
+    ] {
+        write_file(
+            &csv_path2,
+            format!("うに,1,1,1000,名詞,一般,*,*,*,*,*\n{}", csv).as_bytes(),
+        )
+        .unwrap();
+        match compile_user_dic(
+            dicdir.to_str().unwrap(),
+            &[csv_path.to_str().unwrap(), &csv2],
+            user_dic_path.to_str().unwrap(),
+        ) {
+            Err(Error::InvalidFormat { path, line, .. }) => {
+                assert_eq!((path, line), (csv2.clone(), 2))
+            }
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fs::remove_dir_all(&outdir).unwrap();
+}
+
+#[test]
+fn test_compile_error() {
+    let outdir = test_dir("compile-error");
+    assert!(matches!(
+        compile("/something/wrong/path", outdir.to_str().unwrap()),
+        Err(Error::Io { .. })
+    ));
+    let _ = fs::remove_dir_all(&outdir);
+}
+
+#[test]
+fn test_compile_char_property_error() {
+    let dir = test_dir("char-def");
+    fs::create_dir_all(&dir).unwrap();
+    let char_def = dir.join("char.def");
+
+    let mut def = String::from("DEFAULT 0 1 0\nSPACE 0 1 0\n");
+    for i in 0..17 {
+        def.push_str(&format!("CATEGORY{} 0 1 0\n", i));
+    }
+    fs::write(&char_def, &def).unwrap();
+    assert!(matches!(
+        compile_char_property(&char_def),
+        Err(Error::InvalidFormat { line: 19, reason, .. }) if reason == "too many categories"
+    ));
+
+    fs::write(&char_def, "DEFAULT 0 1 0\nSPACE 0 1 16\n").unwrap();
+    assert!(matches!(
+        compile_char_property(&char_def),
+        Err(Error::InvalidFormat { line: 2, reason, .. }) if reason == "length must be less than 16"
+    ));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
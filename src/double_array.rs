@@ -0,0 +1,162 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+// Double-array trie builder compatible with Darts, which MeCab uses.
+//
+// A node at index p has base[p] and check[p].
+// A child by byte c is at base[p] + c + 1 and its check is base[p].
+// The end of a key is at base[p] (code 0), and its base is -value - 1.
+
+struct Sibling {
+    code: usize,
+    left: usize,
+    right: usize,
+}
+
+struct Builder<'a> {
+    keys: &'a [(&'a [u8], u32)],
+    base: Vec<i32>,
+    check: Vec<u32>,
+    used: Vec<bool>,
+    next_check_pos: usize,
+}
+
+impl Builder<'_> {
+    fn resize(&mut self, size: usize) {
+        if size > self.base.len() {
+            let new_size = size.max(self.base.len() * 2);
+            self.base.resize(new_size, 0);
+            self.check.resize(new_size, 0);
+            self.used.resize(new_size, false);
+        }
+    }
+
+    fn fetch(&self, depth: usize, left: usize, right: usize) -> Vec<Sibling> {
+        let mut siblings: Vec<Sibling> = Vec::new();
+        for i in left..right {
+            let key = self.keys[i].0;
+            if key.len() < depth {
+                continue;
+            }
+            let code = if key.len() == depth {
+                0
+            } else {
+                key[depth] as usize + 1
+            };
+            match siblings.last_mut() {
+                Some(last) if last.code == code => last.right = i + 1,
+                _ => siblings.push(Sibling {
+                    code,
+                    left: i,
+                    right: i + 1,
+                }),
+            }
+        }
+        siblings
+    }
+
+    fn insert(&mut self, depth: usize, siblings: &[Sibling]) -> usize {
+        let first_code = siblings[0].code;
+        let last_code = siblings[siblings.len() - 1].code;
+        let mut pos = (first_code + 1).max(self.next_check_pos) - 1;
+        let mut nonzero = 0;
+        let mut first = true;
+
+        let begin = loop {
+            pos += 1;
+            self.resize(pos + 1);
+            if self.check[pos] != 0 {
+                nonzero += 1;
+                continue;
+            } else if first {
+                self.next_check_pos = pos;
+                first = false;
+            }
+
+            let begin = pos - first_code;
+            self.resize(begin + last_code + 1);
+            if self.used[begin] {
+                continue;
+            }
+            if siblings.iter().all(|s| self.check[begin + s.code] == 0) {
+                break begin;
+            }
+        };
+
+        // skip the dense area in the next search
+        if nonzero * 100 >= (pos - self.next_check_pos + 1) * 95 {
+            self.next_check_pos = pos;
+        }
+
+        self.used[begin] = true;
+        for s in siblings.iter() {
+            self.check[begin + s.code] = begin as u32;
+        }
+        for s in siblings.iter() {
+            if s.code == 0 {
+                // end of a key
+                self.base[begin] = -(self.keys[s.left].1 as i32) - 1;
+            } else {
+                let children = self.fetch(depth + 1, s.left, s.right);
+                let child_begin = self.insert(depth + 1, &children);
+                self.base[begin + s.code] = child_begin as i32;
+            }
+        }
+        begin
+    }
+}
+
+// keys must be sorted and unique, values must be less than 0x7fffffff.
+// returns the array of (base, check) as bytes.
+pub fn build(keys: &[(&[u8], u32)]) -> Vec<u8> {
+    let mut builder = Builder {
+        keys,
+        base: vec![0; 8192],
+        check: vec![0; 8192],
+        used: vec![false; 8192],
+        next_check_pos: 0,
+    };
+    builder.base[0] = 1;
+
+    if !keys.is_empty() {
+        let siblings = builder.fetch(0, 0, keys.len());
+        let begin = builder.insert(0, &siblings);
+        builder.base[0] = begin as i32;
+    }
+
+    let size = builder
+        .check
+        .iter()
+        .rposition(|&c| c != 0)
+        .map_or(1, |i| i + 1)
+        // unused area after the last node keeps lookups in range
+        + 0x100 + 1;
+    builder.resize(size);
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(size * 8);
+    for i in 0..size {
+        bytes.extend_from_slice(&builder.base[i].to_le_bytes());
+        bytes.extend_from_slice(&builder.check[i].to_le_bytes());
+    }
+    bytes
+}
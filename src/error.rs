@@ -0,0 +1,113 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+use std::fmt;
+use std::io;
+
+/// Errors returned while loading mecabrc and dictionaries, or compiling dictionaries.
+#[derive(Debug)]
+pub enum Error {
+    /// no mecabrc file in the default locations
+    MecabrcNotFound,
+    /// mecabrc has no `dicdir` entry
+    DicdirNotFound,
+    /// I/O error on the file
+    Io { path: String, source: io::Error },
+    /// broken or unexpected file contents
+    CorruptDictionary { path: String, reason: String },
+    /// unsupported dictionary version
+    VersionMismatch {
+        path: String,
+        expected: u32,
+        found: u32,
+    },
+    /// the file contains bytes which are not UTF-8
+    InvalidUtf8 { path: String },
+    /// invalid value of an option
+    InvalidValue { name: String, value: String },
+    /// syntax error in a source dictionary file, line is 1-based or 0 for the whole file
+    InvalidFormat {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+}
+
+impl Error {
+    pub(crate) fn io(path: &str, source: io::Error) -> Error {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn corrupt(path: &str, reason: &str) -> Error {
+        Error::CorruptDictionary {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MecabrcNotFound => write!(f, "can't find mecabrc"),
+            Error::DicdirNotFound => write!(f, "dicdir is not set in mecabrc"),
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::CorruptDictionary { path, reason } => {
+                write!(f, "{}: broken dictionary: {}", path, reason)
+            }
+            Error::VersionMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: incompatible dictionary version {} (expected {})",
+                path, found, expected
+            ),
+            Error::InvalidUtf8 { path } => write!(f, "{}: invalid UTF-8", path),
+            Error::InvalidValue { name, value } => {
+                write!(f, "invalid value of {}: {}", name, value)
+            }
+            Error::InvalidFormat {
+                path,
+                line: 0,
+                reason,
+            } => write!(f, "{}: {}", path, reason),
+            Error::InvalidFormat { path, line, reason } => {
+                write!(f, "{}:{}: {}", path, line, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
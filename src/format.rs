@@ -0,0 +1,806 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+//! Output format of tokens with MeCab's format escapes.
+//!
+//! - `%m` surface, `%M` surface with the preceding spaces, `%pS` the preceding spaces
+//! - `%H` features, `%f[N]` N-th feature, `%f[N,M]` features joined with ','
+//!   and `%FC[N,M]` joined with the char C (empty if N is out of the features)
+//! - `%h` part of speech id, `%phl` left context id, `%phr` right context id
+//! - `%s` 0 for a known word and 1 for an unknown word
+//! - `%c`, `%pw` word cost, `%pc` accumulated cost from BOS,
+//!   `%pn` cost of the node (`%pC` plus the word cost),
+//!   `%pC` connection cost from the previous node (`Token::conn_cost`)
+//! - `%pP` marginal probability (if the tokenizer computes them)
+//! - `%ps`, `%pe` start and end byte position,
+//!   `%pl` byte length of the surface, `%pL` with the preceding spaces
+//! - `%S` input sentence, `%L` byte length of the input sentence, `%%` '%'
+//! - `\0 \a \b \t \n \v \f \r \s(space) \\`
+//!
+//! bos, eos and eon formats can have only `%S`, `%L` and `%%`.
+use super::Error;
+use super::csv::split_csv;
+use super::mecabrc::Mecabrc;
+use super::tokenizer::Token;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Text(String),
+    Sentence,
+    SentenceLen,
+    Surface,
+    SpaceSurface,
+    Space,
+    Feature,
+    Fields(String, Vec<usize>),
+    PosId,
+    Stat,
+    WordCost,
+    Cost,
+    NodeCost,
+    ConnectionCost,
+    Start,
+    End,
+    Len,
+    SpaceLen,
+    LeftId,
+    RightId,
+    Prob,
+}
+
+/// A parsed format string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    items: Vec<Item>,
+}
+
+impl Template {
+    /// Parse the format. name (e.g. "node-format") is used in the error.
+    pub fn new(name: &str, format: &str) -> Result<Template, Error> {
+        let error = || Error::InvalidValue {
+            name: name.to_string(),
+            value: format.to_string(),
+        };
+        let mut items: Vec<Item> = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            let item = match c {
+                '\\' => {
+                    text.push(match chars.next().ok_or_else(error)? {
+                        '0' => '\0',
+                        'a' => '\x07',
+                        'b' => '\x08',
+                        't' => '\t',
+                        'n' => '\n',
+                        'v' => '\x0b',
+                        'f' => '\x0c',
+                        'r' => '\r',
+                        's' => ' ',
+                        c => c,
+                    });
+                    continue;
+                }
+                '%' => match chars.next().ok_or_else(error)? {
+                    '%' => {
+                        text.push('%');
+                        continue;
+                    }
+                    'S' => Item::Sentence,
+                    'L' => Item::SentenceLen,
+                    'm' => Item::Surface,
+                    'M' => Item::SpaceSurface,
+                    'H' => Item::Feature,
+                    'h' => Item::PosId,
+                    's' => Item::Stat,
+                    'c' => Item::WordCost,
+                    'f' => Item::Fields(
+                        ",".to_string(),
+                        parse_indexes(&mut chars).ok_or_else(error)?,
+                    ),
+                    'F' => {
+                        let separator = chars.next().ok_or_else(error)?.to_string();
+                        Item::Fields(separator, parse_indexes(&mut chars).ok_or_else(error)?)
+                    }
+                    'p' => match chars.next().ok_or_else(error)? {
+                        'S' => Item::Space,
+                        'w' => Item::WordCost,
+                        'c' => Item::Cost,
+                        'n' => Item::NodeCost,
+                        'C' => Item::ConnectionCost,
+                        's' => Item::Start,
+                        'e' => Item::End,
+                        'l' => Item::Len,
+                        'L' => Item::SpaceLen,
+                        'P' => Item::Prob,
+                        'h' => match chars.next().ok_or_else(error)? {
+                            'l' => Item::LeftId,
+                            'r' => Item::RightId,
+                            _ => return Err(error()),
+                        },
+                        _ => return Err(error()),
+                    },
+                    _ => return Err(error()),
+                },
+                c => {
+                    text.push(c);
+                    continue;
+                }
+            };
+            if !text.is_empty() {
+                items.push(Item::Text(std::mem::take(&mut text)));
+            }
+            items.push(item);
+        }
+        if !text.is_empty() {
+            items.push(Item::Text(text));
+        }
+        Ok(Template { items })
+    }
+
+    // parse the format which has no node escapes
+    fn new_sentence(name: &str, format: &str) -> Result<Template, Error> {
+        let template = Template::new(name, format)?;
+        if template
+            .items
+            .iter()
+            .all(|item| matches!(item, Item::Text(_) | Item::Sentence | Item::SentenceLen))
+        {
+            Ok(template)
+        } else {
+            Err(Error::InvalidValue {
+                name: name.to_string(),
+                value: format.to_string(),
+            })
+        }
+    }
+
+    fn write_sentence(&self, out: &mut String, s: &str) {
+        for item in self.items.iter() {
+            match item {
+                Item::Text(text) => out.push_str(text),
+                Item::Sentence => out.push_str(s),
+                Item::SentenceLen => out.push_str(&s.len().to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Write i-th token of the tokens of the sentence s.
+    pub fn write_token(&self, out: &mut String, s: &str, tokens: &[Token], i: usize) {
+        let prev_end = match i {
+            0 => 0,
+            _ => tokens[i - 1].end,
+        };
+        self.write_node(out, s, &tokens[i], prev_end);
+    }
+
+    // the token after the previous token which ends at prev_end
+    fn write_node(&self, out: &mut String, s: &str, token: &Token, prev_end: usize) {
+        let space = s.get(prev_end..token.start).unwrap_or("");
+        for item in self.items.iter() {
+            let value = match item {
+                Item::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Item::Sentence => {
+                    out.push_str(s);
+                    continue;
+                }
+                Item::Surface => {
+                    out.push_str(&token.surface);
+                    continue;
+                }
+                Item::SpaceSurface => {
+                    out.push_str(space);
+                    out.push_str(&token.surface);
+                    continue;
+                }
+                Item::Space => {
+                    out.push_str(space);
+                    continue;
+                }
+                Item::Feature => {
+                    out.push_str(&token.feature);
+                    continue;
+                }
+                Item::Prob => {
+                    out.push_str(&format!("{:.6}", token.prob));
+                    continue;
+                }
+                Item::Fields(separator, indexes) => {
+                    let fields = split_csv(&token.feature, usize::MAX);
+                    for (j, &index) in indexes.iter().enumerate() {
+                        if j > 0 {
+                            out.push_str(separator);
+                        }
+                        out.push_str(fields.get(index).map_or("", |f| f.as_str()));
+                    }
+                    continue;
+                }
+                Item::SentenceLen => s.len() as i64,
+                Item::PosId => token.posid as i64,
+                Item::Stat => token.unknown as i64,
+                Item::WordCost => token.wcost as i64,
+                Item::Cost => token.cost as i64,
+                Item::NodeCost => (token.conn_cost + token.wcost as i32) as i64,
+                Item::ConnectionCost => token.conn_cost as i64,
+                Item::Start => token.start as i64,
+                Item::End => token.end as i64,
+                Item::Len => (token.end - token.start) as i64,
+                Item::SpaceLen => (token.end - prev_end) as i64,
+                Item::LeftId => token.left_id as i64,
+                Item::RightId => token.right_id as i64,
+            };
+            out.push_str(&value.to_string());
+        }
+    }
+}
+
+// "[N,M,...]" of %f and %F
+fn parse_indexes(chars: &mut std::str::Chars) -> Option<Vec<usize>> {
+    if chars.next()? != '[' {
+        return None;
+    }
+    let mut indexes: Vec<usize> = Vec::new();
+    let mut number = String::new();
+    loop {
+        match chars.next()? {
+            c @ '0'..='9' => number.push(c),
+            c @ (',' | ']') => {
+                indexes.push(number.parse().ok()?);
+                number.clear();
+                if c == ']' {
+                    return Some(indexes);
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Formats of the tokens (node-format and unk-format),
+/// the beginning and end of a sentence (bos-format and eos-format) and the end of N-best (eon-format).
+///
+/// The default is the same as MeCab, "%m\t%H\n" for each token and "EOS\n" after a sentence.
+#[derive(Debug, Clone)]
+pub struct Format {
+    node: Template,
+    unk: Option<Template>,
+    bos: Template,
+    eos: Template,
+    eon: Template,
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format {
+            node: Template::new("node-format", "%m\\t%H\\n").unwrap(),
+            unk: None,
+            bos: Template::new("bos-format", "").unwrap(),
+            eos: Template::new("eos-format", "EOS\\n").unwrap(),
+            eon: Template::new("eon-format", "").unwrap(),
+        }
+    }
+}
+
+impl Format {
+    /// Formats of mecabrc, node-format-NAME etc. with the name or output-format-type of mecabrc.
+    ///
+    /// If node-format-NAME is not in mecabrc, the built-in format of the name is used.
+    /// The defaults are used for the formats which are not in mecabrc.
+    pub fn from_mecabrc(rc: &Mecabrc, name: Option<&str>) -> Result<Format, Error> {
+        let name = name.or(rc.output_format_type());
+        let mut format = Format::default();
+        match (rc.node_format(name), name) {
+            (Some(node_format), _) => format = format.node_format(node_format)?,
+            (None, Some(name)) => {
+                return Format::builtin(name).ok_or_else(|| Error::InvalidValue {
+                    name: "output-format-type".to_string(),
+                    value: name.to_string(),
+                });
+            }
+            (None, None) => {}
+        }
+        if let Some(unk_format) = rc.unk_format(name) {
+            format = format.unk_format(unk_format)?;
+        }
+        if let Some(bos_format) = rc.bos_format(name) {
+            format = format.bos_format(bos_format)?;
+        }
+        if let Some(eos_format) = rc.eos_format(name) {
+            format = format.eos_format(eos_format)?;
+        }
+        if let Some(eon_format) = rc.eon_format(name) {
+            format = format.eon_format(eon_format)?;
+        }
+        Ok(format)
+    }
+
+    /// Built-in formats
+    ///
+    /// - wakati: surfaces separated by spaces
+    /// - yomi: readings (the 8th feature of ipadic)
+    /// - chasen: ChaSen format
+    /// - simple: surface and part of speech
+    /// - dump: all fields of the tokens
+    pub fn builtin(name: &str) -> Option<Format> {
+        let (node, unk, eos) = match name {
+            "wakati" => ("%m ", None, "\\n"),
+            "yomi" => ("%pS%f[7]", Some("%M"), "\\n"),
+            "chasen" => (
+                "%m\\t%f[7]\\t%f[6]\\t%F-[0,1,2,3]\\t%f[4]\\t%f[5]\\n",
+                Some("%m\\t%m\\t%m\\t%F-[0,1,2,3]\\t\\t\\n"),
+                "EOS\\n",
+            ),
+            "simple" => ("%m\\t%F-[0,1,2,3]\\n", None, "EOS\\n"),
+            "dump" => (
+                "%m\\t%H\\t%ps\\t%pe\\t%phl\\t%phr\\t%h\\t%s\\t%c\\t%pC\\t%pc\\n",
+                None,
+                "EOS\\n",
+            ),
+            _ => return None,
+        };
+        let mut format = Format::default()
+            .node_format(node)
+            .ok()?
+            .eos_format(eos)
+            .ok()?;
+        if let Some(unk) = unk {
+            format = format.unk_format(unk).ok()?;
+        }
+        Some(format)
+    }
+
+    pub fn node_format(mut self, format: &str) -> Result<Format, Error> {
+        self.node = Template::new("node-format", format)?;
+        Ok(self)
+    }
+
+    /// format of unknown words, node-format is used if not given
+    pub fn unk_format(mut self, format: &str) -> Result<Format, Error> {
+        self.unk = Some(Template::new("unk-format", format)?);
+        Ok(self)
+    }
+
+    pub fn bos_format(mut self, format: &str) -> Result<Format, Error> {
+        self.bos = Template::new_sentence("bos-format", format)?;
+        Ok(self)
+    }
+
+    pub fn eos_format(mut self, format: &str) -> Result<Format, Error> {
+        self.eos = Template::new_sentence("eos-format", format)?;
+        Ok(self)
+    }
+
+    pub fn eon_format(mut self, format: &str) -> Result<Format, Error> {
+        self.eon = Template::new_sentence("eon-format", format)?;
+        Ok(self)
+    }
+
+    /// Write the tokens of the sentence s with bos and eos.
+    pub fn write(&self, out: &mut String, s: &str, tokens: &[Token]) {
+        self.bos.write_sentence(out, s);
+        for (i, token) in tokens.iter().enumerate() {
+            let template = match &self.unk {
+                Some(unk) if token.unknown => unk,
+                _ => &self.node,
+            };
+            template.write_token(out, s, tokens, i);
+        }
+        self.eos.write_sentence(out, s);
+    }
+
+    /// Write all the words of the sentence s returned by `Tokenizer::all_morphs()` with bos and eos.
+    ///
+    /// Each word is written without the previous one, so `%pS` is empty,
+    /// and `%pn` and `%pC` are from the best previous word.
+    pub fn write_all_morphs(&self, out: &mut String, s: &str, morphs: &[Vec<Token>]) {
+        self.bos.write_sentence(out, s);
+        for token in morphs.iter().flatten() {
+            let template = match &self.unk {
+                Some(unk) if token.unknown => unk,
+                _ => &self.node,
+            };
+            template.write_node(out, s, token, token.start);
+        }
+        self.eos.write_sentence(out, s);
+    }
+
+    /// The tokens of the sentence s with bos and eos.
+    pub fn format(&self, s: &str, tokens: &[Token]) -> String {
+        let mut out = String::new();
+        self.write(&mut out, s, tokens);
+        out
+    }
+
+    /// Write the end of N-best of the sentence s.
+    pub fn write_eon(&self, out: &mut String, s: &str) {
+        self.eon.write_sentence(out, s);
+    }
+}
+
+// JSON string literal of s
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// "cost": cost, "tokens": [...]
+fn write_json_path(out: &mut String, tokens: &[Token], cost: i32) {
+    out.push_str(&format!("\"cost\":{},\"tokens\":", cost));
+    write_json_tokens(out, tokens);
+}
+
+// [{"surface": .., ...}, ...]
+fn write_json_tokens(out: &mut String, tokens: &[Token]) {
+    out.push('[');
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"surface\":");
+        write_json_string(out, &token.surface);
+        out.push_str(",\"features\":[");
+        for (j, field) in split_csv(&token.feature, usize::MAX).iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            write_json_string(out, field);
+        }
+        out.push_str(&format!(
+            "],\"start\":{},\"end\":{},\"char_start\":{},\"char_end\":{},\
+             \"left_id\":{},\"right_id\":{},\"posid\":{},\"wcost\":{},\"cost\":{},\"unknown\":{},\"prob\":{}}}",
+            token.start,
+            token.end,
+            token.char_start,
+            token.char_end,
+            token.left_id,
+            token.right_id,
+            token.posid,
+            token.wcost,
+            token.cost,
+            token.unknown,
+            // NaN and infinity are not JSON numbers
+            if token.prob.is_finite() {
+                token.prob.to_string()
+            } else {
+                "null".to_string()
+            }
+        ));
+    }
+    out.push(']');
+}
+
+/// Write the tokens of the sentence s as a JSON object in a line.
+///
+/// `{"sentence": s, "cost": cost, "tokens": [{"surface": .., "features": [..], "start": .., ...}]}`
+pub fn write_json(out: &mut String, s: &str, tokens: &[Token], cost: i32) {
+    out.push_str("{\"sentence\":");
+    write_json_string(out, s);
+    out.push(',');
+    write_json_path(out, tokens, cost);
+    out.push('}');
+}
+
+/// Write all the words of the sentence s grouped by the start position as a JSON object in a line.
+///
+/// `{"sentence": s, "morphs": [[{"surface": .., ...}, ...], ...]}`
+pub fn write_json_all_morphs(out: &mut String, s: &str, morphs: &[Vec<Token>]) {
+    out.push_str("{\"sentence\":");
+    write_json_string(out, s);
+    out.push_str(",\"morphs\":[");
+    for (i, tokens) in morphs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_tokens(out, tokens);
+    }
+    out.push_str("]}");
+}
+
+/// Write N-best tokens and their costs of the sentence s as a JSON object in a line.
+///
+/// `{"sentence": s, "nbest": [{"cost": cost, "tokens": [..]}, ...]}`
+pub fn write_json_n_best(out: &mut String, s: &str, paths: &[(Vec<Token>, i32)]) {
+    out.push_str("{\"sentence\":");
+    write_json_string(out, s);
+    out.push_str(",\"nbest\":[");
+    for (i, (tokens, cost)) in paths.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push('{');
+        write_json_path(out, tokens, *cost);
+        out.push('}');
+    }
+    out.push_str("]}");
+}
+
+#[cfg(test)]
+use super::tests::fixture_rcfile;
+
+#[cfg(test)]
+fn test_token(surface: &str, feature: &str, start: usize, cost: i32, unknown: bool) -> Token {
+    Token {
+        surface: surface.to_string(),
+        feature: feature.to_string(),
+        left_id: 10,
+        right_id: 20,
+        posid: 30,
+        wcost: 100,
+        cost,
+        conn_cost: -50,
+        start,
+        end: start + surface.len(),
+        char_start: start,
+        char_end: start + surface.len(),
+        unknown,
+        prob: 0.25,
+    }
+}
+
+#[test]
+fn test_template() {
+    let s = "ab  cd";
+    let tokens = vec![
+        test_token("ab", "名詞,一般,*,\"x,y\",エービー", 0, 500, false),
+        test_token("cd", "名詞,固有名詞", 4, 1200, true),
+    ];
+    let render = |format: &str, i: usize| {
+        let mut out = String::new();
+        Template::new("node-format", format)
+            .unwrap()
+            .write_token(&mut out, s, &tokens, i);
+        out
+    };
+
+    assert_eq!(
+        render("%m\\t%H\\n", 0),
+        "ab\t名詞,一般,*,\"x,y\",エービー\n"
+    );
+    assert_eq!(render("[%M][%pS][%m]", 1), "[  cd][  ][cd]");
+    assert_eq!(
+        render("%f[0]/%f[3]/%f[4,0]/%F-[0,1]/%f[9]", 0),
+        "名詞/x,y/エービー,名詞/名詞-一般/"
+    );
+    assert_eq!(
+        render("%h %phl %phr %s %c %pw %pc %pn %pC", 1),
+        "30 10 20 1 100 100 1200 50 -50"
+    );
+    assert_eq!(render("%ps %pe %pl %pL %L", 1), "4 6 2 4 6");
+    assert_eq!(render("%pP", 1), "0.250000");
+    assert_eq!(render("%S\\s100%%\\\\", 0), "ab  cd 100%\\");
+
+    for format in [
+        "%", "%x", "%p", "%px", "%ph", "%f", "%f[", "%f[1", "%f[a]", "%F[0]", "\\",
+    ] {
+        assert!(
+            matches!(
+                Template::new("node-format", format),
+                Err(Error::InvalidValue { .. })
+            ),
+            "{}",
+            format
+        );
+    }
+}
+
+#[test]
+fn test_builtin_format() {
+    let s = "ab cd";
+    let tokens = vec![
+        test_token(
+            "ab",
+            "名詞,一般,*,*,*,*,ab,エービー,エービー",
+            0,
+            500,
+            false,
+        ),
+        test_token("cd", "名詞,固有名詞,組織,*,*,*,*", 3, 1200, true),
+    ];
+    let format = |name: &str| Format::builtin(name).unwrap().format(s, &tokens);
+
+    assert_eq!(format("wakati"), "ab cd \n");
+    assert_eq!(format("yomi"), "エービー cd\n");
+    assert_eq!(
+        format("chasen"),
+        "ab\tエービー\tab\t名詞-一般-*-*\t*\t*\n\
+         cd\tcd\tcd\t名詞-固有名詞-組織-*\t\t\n\
+         EOS\n"
+    );
+    assert_eq!(
+        format("simple"),
+        "ab\t名詞-一般-*-*\ncd\t名詞-固有名詞-組織-*\nEOS\n"
+    );
+    assert_eq!(
+        format("dump"),
+        "ab\t名詞,一般,*,*,*,*,ab,エービー,エービー\t0\t2\t10\t20\t30\t0\t100\t-50\t500\n\
+         cd\t名詞,固有名詞,組織,*,*,*,*\t3\t5\t10\t20\t30\t1\t100\t-50\t1200\n\
+         EOS\n"
+    );
+    assert!(Format::builtin("foo").is_none());
+}
+
+#[test]
+fn test_format() {
+    let s = "ab cd";
+    let tokens = vec![
+        test_token("ab", "名詞,一般", 0, 500, false),
+        test_token("cd", "名詞,固有名詞", 3, 1200, true),
+    ];
+    assert_eq!(
+        Format::default().format(s, &tokens),
+        "ab\t名詞,一般\ncd\t名詞,固有名詞\nEOS\n"
+    );
+
+    let format = Format::default()
+        .node_format("%m/")
+        .unwrap()
+        .unk_format("?%m/")
+        .unwrap()
+        .bos_format("[%S]")
+        .unwrap()
+        .eos_format("%L\\n")
+        .unwrap()
+        .eon_format("EON\\n")
+        .unwrap();
+    let mut out = format.format(s, &tokens);
+    format.write_eon(&mut out, s);
+    assert_eq!(out, "[ab cd]ab/?cd/5\nEON\n");
+    assert!(Format::default().eos_format("%m").is_err());
+
+    // all-morphs
+    let mut cd = tokens[1].clone();
+    cd.conn_cost = 600;
+    let morphs = vec![
+        vec![tokens[0].clone(), test_token("a", "名詞", 0, 300, true)],
+        vec![cd],
+    ];
+    let mut out = String::new();
+    Format::default()
+        .node_format("%M %ps %pn %pC\\n")
+        .unwrap()
+        .write_all_morphs(&mut out, s, &morphs);
+    assert_eq!(out, "ab 0 50 -50\na 0 50 -50\ncd 3 700 600\nEOS\n");
+
+    let dir = std::env::temp_dir().join(format!("awabi-format-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("mecabrc");
+    std::fs::write(
+        &path,
+        "node-format-foo = %m \n\
+         eos-format-foo = \\n\n\
+         node-format = %m\\n\n",
+    )
+    .unwrap();
+    let rc = Mecabrc::open(path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        Format::from_mecabrc(&rc, Some("foo"))
+            .unwrap()
+            .format(s, &tokens),
+        "ab cd \n"
+    );
+    assert_eq!(
+        Format::from_mecabrc(&rc, None).unwrap().format(s, &tokens),
+        "ab\ncd\nEOS\n"
+    );
+    assert!(matches!(
+        Format::from_mecabrc(&rc, Some("bar")),
+        Err(Error::InvalidValue { name, .. }) if name == "output-format-type"
+    ));
+    assert_eq!(
+        Format::from_mecabrc(&rc, Some("wakati"))
+            .unwrap()
+            .format(s, &tokens),
+        "ab cd \n"
+    );
+
+    // output-format-type
+    std::fs::write(&path, "output-format-type = simple\n").unwrap();
+    let rc = Mecabrc::open(path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        Format::from_mecabrc(&rc, None).unwrap().format(s, &tokens),
+        "ab\t名詞-一般--\ncd\t名詞-固有名詞--\nEOS\n"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_json() {
+    let s = "a\"b\\ c";
+    let tokens = vec![
+        test_token("a\"b\\", "名詞,\"x,y\",\t", 0, 500, false),
+        test_token("c", "記号", 5, 1200, true),
+    ];
+
+    let mut out = String::new();
+    write_json(&mut out, s, &tokens, 1500);
+    assert_eq!(
+        out,
+        "{\"sentence\":\"a\\\"b\\\\ c\",\"cost\":1500,\"tokens\":[\
+         {\"surface\":\"a\\\"b\\\\\",\"features\":[\"名詞\",\"x,y\",\"\\t\"],\
+         \"start\":0,\"end\":4,\"char_start\":0,\"char_end\":4,\
+         \"left_id\":10,\"right_id\":20,\"posid\":30,\"wcost\":100,\"cost\":500,\"unknown\":false,\"prob\":0.25},\
+         {\"surface\":\"c\",\"features\":[\"記号\"],\
+         \"start\":5,\"end\":6,\"char_start\":5,\"char_end\":6,\
+         \"left_id\":10,\"right_id\":20,\"posid\":30,\"wcost\":100,\"cost\":1200,\"unknown\":true,\"prob\":0.25}]}"
+    );
+
+    let mut out = String::new();
+    write_json_n_best(
+        &mut out,
+        "\u{1}",
+        &[(Vec::new(), 10), (tokens[1..].to_vec(), 20)],
+    );
+    assert!(out.starts_with(
+        "{\"sentence\":\"\\u0001\",\"nbest\":[{\"cost\":10,\"tokens\":[]},{\"cost\":20,\"tokens\":[{\"surface\":\"c\","
+    ));
+    assert!(out.ends_with("\"unknown\":true,\"prob\":0.25}]}]}"));
+
+    let mut out = String::new();
+    write_json_all_morphs(&mut out, "c", &[tokens[1..].to_vec(), tokens[1..].to_vec()]);
+    assert!(out.starts_with("{\"sentence\":\"c\",\"morphs\":[[{\"surface\":\"c\","));
+    assert!(out.contains("\"prob\":0.25}],[{\"surface\":\"c\","));
+    assert!(out.ends_with("\"prob\":0.25}]]}"));
+    let mut t = tokens[1].clone();
+    t.prob = f64::NAN;
+    let mut out = String::new();
+    write_json(&mut out, "c", &[t], 0);
+    assert!(out.ends_with("\"unknown\":true,\"prob\":null}]}"));
+    let mut out = String::new();
+    write_json_all_morphs(&mut out, "", &[]);
+    assert_eq!(out, "{\"sentence\":\"\",\"morphs\":[]}");
+}
+
+#[test]
+fn test_format_mecabrc() {
+    let rcfile = fixture_rcfile(
+        "format",
+        "node-format-foo = %m/%f[0]/%s \nunk-format-foo = %m/?/%s \neos-format-foo = \\n\n",
+    );
+    let tokenizer = super::tokenizer::Tokenizer::new(rcfile.to_str()).unwrap();
+
+    let s = "東京 abc";
+    let tokens = tokenizer.parse(s);
+    assert!(!tokens[0].unknown);
+    assert!(tokens[1].unknown);
+    let format = Format::from_mecabrc(tokenizer.mecabrc(), Some("foo")).unwrap();
+    assert_eq!(format.format(s, &tokens), "東京/名詞/0 abc/?/1 \n");
+    // cost-factor from dicrc
+    assert_eq!(tokenizer.mecabrc().cost_factor().unwrap(), Some(800));
+
+    std::fs::remove_file(rcfile).unwrap();
+}
@@ -25,9 +25,10 @@ use super::dic::{DicEntry, Matrix};
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::ptr;
-use std::rc::Rc;
 use std::slice;
-use std::str;
+
+// no back node (BOS)
+const NO_NODE: usize = usize::MAX;
 
 #[derive(Debug)]
 pub struct Node {
@@ -35,18 +36,29 @@ pub struct Node {
     pub original_len: usize,
     pub feature_ptr: *const u8,
     pub feature_len: usize,
-    pos: i32,
-    epos: i32,
-    index: i32,
-    left_id: i32,
-    right_id: i32,
-    cost: i32,
-    min_cost: i32,
-    back_pos: i32,
-    back_index: i32,
+    pub pos: i32,
+    pub epos: i32,
+    pub posid: u16,
+    pub left_id: i32,
+    pub right_id: i32,
+    pub cost: i32,
+    pub min_cost: i32,
+    // index of the best previous node in the lattice
+    back: usize,
     skip: bool,
+    pub unknown: bool,
+    // forward and backward log scores, and the marginal probability
+    pub alpha: f64,
+    pub beta: f64,
+    pub prob: f64,
 }
 
+// SAFETY: the pointers are only read, and point to the input string, the dictionaries,
+// the runtime user dictionary and the constraints which are immutable while the nodes are used.
+// The tokenizer removes the nodes with Lattice::reset() before returning the results,
+// so a lattice sent to another thread with Workspace has no pointers to the freed memory.
+unsafe impl Send for Node {}
+
 impl Node {
     fn bos() -> Node {
         Node {
@@ -56,14 +68,17 @@ impl Node {
             feature_len: 0,
             pos: 0,
             epos: 1,
-            index: 0,
+            posid: 0,
             left_id: -1,
             right_id: 0,
             cost: 0,
             min_cost: 0,
-            back_pos: -1,
-            back_index: -1,
+            back: NO_NODE,
             skip: false,
+            unknown: false,
+            alpha: 0.0,
+            beta: 0.0,
+            prob: 0.0,
         }
     }
 
@@ -73,21 +88,23 @@ impl Node {
             original_len: 0,
             feature_ptr: ptr::null(),
             feature_len: 0,
-            pos: pos,
+            pos,
             epos: pos + 1,
-            index: 0,
+            posid: 0,
             left_id: 0,
             right_id: -1,
             cost: 0,
             min_cost: 0x7FFFFFFF,
-            back_pos: -1,
-            back_index: -1,
+            back: NO_NODE,
             skip: false,
+            unknown: false,
+            alpha: 0.0,
+            beta: 0.0,
+            prob: 0.0,
         }
     }
 
     pub fn new(e: DicEntry) -> Node {
-        let index: i32 = e.posid as i32;
         let left_id: i32 = e.lc_attr as i32;
         let right_id: i32 = e.rc_attr as i32;
         let cost: i32 = e.wcost as i32;
@@ -100,14 +117,17 @@ impl Node {
             feature_len: e.feature_len,
             pos: 0,
             epos: 0,
-            index,
+            posid: e.posid,
             left_id,
             right_id,
             cost,
             min_cost: 0x7FFFFFFF,
-            back_pos: -1,
-            back_index: -1,
+            back: NO_NODE,
             skip,
+            unknown: e.unknown,
+            alpha: 0.0,
+            beta: 0.0,
+            prob: 0.0,
         }
     }
 
@@ -121,17 +141,16 @@ impl Node {
 
     pub fn original_to_string(&self) -> String {
         unsafe {
-            str::from_utf8(slice::from_raw_parts(self.original_ptr, self.original_len))
-                .unwrap()
-                .to_string()
+            String::from_utf8_lossy(slice::from_raw_parts(self.original_ptr, self.original_len))
+                .into_owned()
         }
     }
 
     pub fn feature_to_string(&self) -> String {
+        // dictionaries not in UTF-8 (e.g. EUC-JP) should not make a panic
         unsafe {
-            str::from_utf8(slice::from_raw_parts(self.feature_ptr, self.feature_len))
-                .unwrap()
-                .to_string()
+            String::from_utf8_lossy(slice::from_raw_parts(self.feature_ptr, self.feature_len))
+                .into_owned()
         }
     }
 
@@ -143,47 +162,66 @@ impl Node {
     }
 }
 
-#[derive(Debug)]
+/// Lattice of a sentence, which can be cleared and reused for the next sentence
+/// without freeing the buffers.
+#[derive(Debug, Default)]
 pub struct Lattice {
-    snodes: Vec<Vec<Rc<Node>>>,
-    enodes: Vec<Vec<Rc<Node>>>,
+    // arena of the nodes, snodes and enodes have the indexes of them
+    nodes: Vec<Node>,
+    snodes: Vec<Vec<usize>>,
+    enodes: Vec<Vec<usize>>,
+    size: usize,
     p: i32,
+    // buffer of forward_backward()
+    prevs: Vec<usize>,
 }
 
 impl Lattice {
-    pub fn new(size: usize) -> Lattice {
-        let mut snodes: Vec<Vec<Rc<Node>>> = Vec::with_capacity(size + 2);
-        let mut enodes: Vec<Vec<Rc<Node>>> = Vec::with_capacity(size + 3);
-
-        enodes.push(Vec::new());
+    // remove the nodes which point to the input and the dictionaries,
+    // the allocated buffers are kept
+    pub fn reset(&mut self) {
+        let used = self.size + 3;
+        self.nodes.clear();
+        for nodes in self.snodes.iter_mut().take(used) {
+            nodes.clear();
+        }
+        for nodes in self.enodes.iter_mut().take(used) {
+            nodes.clear();
+        }
+        self.size = 0;
+    }
 
-        for _ in 0..(size + 2) {
-            snodes.push(Vec::new());
-            enodes.push(Vec::new());
+    // start a sentence of size bytes, the allocated buffers are kept
+    pub fn clear(&mut self, size: usize) {
+        self.reset();
+        if self.snodes.len() < size + 2 {
+            self.snodes.resize_with(size + 2, Vec::new);
         }
-        let bos = Rc::new(Node::bos());
-        snodes[0].push(Rc::clone(&bos));
-        enodes[1].push(bos);
-
-        Lattice {
-            snodes,
-            enodes,
-            p: 1,
+        if self.enodes.len() < size + 3 {
+            self.enodes.resize_with(size + 3, Vec::new);
         }
+        self.size = size;
+        self.p = 1;
+
+        self.nodes.push(Node::bos());
+        self.snodes[0].push(0);
+        self.enodes[1].push(0);
     }
 
     pub fn add(&mut self, mut node: Node, matrix: &Matrix) {
         let mut min_cost = node.min_cost;
-        let mut best_node = &self.enodes[self.p as usize][0];
+        let mut best_node = self.enodes[self.p as usize][0];
 
-        for enode in &self.enodes[self.p as usize] {
+        for &i in self.enodes[self.p as usize].iter() {
+            let enode = &self.nodes[i];
             if enode.skip {
-                for enode2 in &self.enodes[enode.pos as usize] {
+                for &j in self.enodes[enode.pos as usize].iter() {
+                    let enode2 = &self.nodes[j];
                     let cost = enode2.min_cost
                         + matrix.get_trans_cost(enode2.right_id as u16, node.left_id as u16);
                     if cost < min_cost {
                         min_cost = cost;
-                        best_node = enode2;
+                        best_node = j;
                     }
                 }
             } else {
@@ -191,30 +229,26 @@ impl Lattice {
                     + matrix.get_trans_cost(enode.right_id as u16, node.left_id as u16);
                 if cost < min_cost {
                     min_cost = cost;
-                    best_node = enode;
+                    best_node = i;
                 }
             }
         }
 
         node.min_cost = min_cost + node.cost;
-        node.back_index = best_node.index;
-        node.back_pos = best_node.pos;
+        node.back = best_node;
         node.pos = self.p;
         node.epos = self.p + node.node_len();
 
-        node.index = self.snodes[self.p as usize].len() as i32;
-
-        let node = Rc::new(node);
-        let node_pos = node.pos;
-        let node_epos = node.epos;
-        self.snodes[node_pos as usize].push(Rc::clone(&node));
-        self.enodes[node_epos as usize].push(node);
+        let index = self.nodes.len();
+        self.snodes[node.pos as usize].push(index);
+        self.enodes[node.epos as usize].push(index);
+        self.nodes.push(node);
     }
 
     pub fn forward(&mut self) -> usize {
         let old_p = self.p;
         self.p += 1;
-        while self.enodes[self.p as usize].len() == 0 {
+        while self.enodes[self.p as usize].is_empty() {
             self.p += 1;
         }
         (self.p - old_p) as usize
@@ -222,123 +256,238 @@ impl Lattice {
 
     pub fn end(&mut self, matrix: &Matrix) {
         self.add(Node::eos(self.p), matrix);
-        // self.snodes.truncate((self.p + 1) as usize);
-        // self.enodes.truncate((self.p + 2) as usize);
-    }
-
-    pub fn backward(&self) -> Vec<Rc<Node>> {
-        // last node should EOS
-        assert!(&self.snodes[self.snodes.len() - 1][0].is_eos());
-
-        let mut shortest_path: Vec<Rc<Node>> = Vec::new();
-        let mut pos: i32 = self.snodes.len() as i32 - 1;
-        let mut index = 0;
-        while pos >= 0 {
-            let node = &self.snodes[pos as usize][index];
-            index = node.back_index as usize;
-            pos = node.back_pos;
-            shortest_path.push(Rc::clone(&node));
-        }
-
-        shortest_path.reverse();
-        shortest_path
     }
 
-    pub fn backward_astar(&self, mut n: u32, matrix: &Matrix) -> Vec<Vec<Rc<Node>>> {
-        let mut paths: Vec<Vec<Rc<Node>>> = Vec::new();
-        let epos: i32 = self.enodes.len() as i32 - 1;
-        let node = &self.enodes[epos as usize][0];
-        assert!(&node.is_eos());
+    /// All the nodes after BOS in the order of the start position, without SPACE,
+    /// with their best previous nodes.
+    pub fn nodes_with_back(&self) -> impl Iterator<Item = (&Node, &Node)> {
+        self.nodes
+            .iter()
+            .filter(|node| !node.skip && node.back != NO_NODE)
+            .map(|node| (&self.nodes[node.back], node))
+    }
 
-        let mut pq: BinaryHeap<BackwardPath> = BinaryHeap::new();
-        pq.push(BackwardPath::new(Rc::clone(&node), None, matrix));
+    // indexes of the nodes which end at the position, SPACE is skipped like add()
+    fn prev_nodes(&self, pos: i32) -> impl Iterator<Item = usize> + '_ {
+        self.enodes[pos as usize]
+            .iter()
+            .flat_map(move |i| {
+                let node = &self.nodes[*i];
+                if node.skip {
+                    self.enodes[node.pos as usize].as_slice()
+                } else {
+                    slice::from_ref(i)
+                }
+            })
+            .copied()
+    }
 
-        while pq.len() > 0 && n > 0 {
-            let bp = pq.pop().unwrap();
-            if bp.is_complete() {
-                let mut path = bp.back_path;
-                path.reverse();
-                paths.push(path);
-                n -= 1;
+    // marginal probabilities of the nodes by the forward-backward algorithm,
+    // the scores are exp(-theta * cost)
+    pub fn forward_backward(&mut self, theta: f64, matrix: &Matrix) {
+        // the previous nodes are before the node in the arena
+        for i in 0..self.nodes.len() {
+            let node = &self.nodes[i];
+            let alpha = if node.is_bos() {
+                0.0
             } else {
-                let new_node = &bp.back_path[&bp.back_path.len() - 1];
-                let epos = new_node.epos - new_node.node_len();
-                for node in self.enodes[epos as usize].iter() {
-                    pq.push(BackwardPath::new(Rc::clone(&node), Some(&bp), matrix));
-                }
+                log_sum_exp(self.prev_nodes(node.pos).map(|j| {
+                    let prev = &self.nodes[j];
+                    prev.alpha
+                        - theta
+                            * (matrix.get_trans_cost(prev.right_id as u16, node.left_id as u16)
+                                + node.cost) as f64
+                }))
+            };
+            self.nodes[i].alpha = alpha;
+            self.nodes[i].beta = f64::NEG_INFINITY;
+        }
+
+        let eos = self.eos();
+        self.nodes[eos].beta = 0.0;
+        for i in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[i];
+            if node.is_bos() || node.beta == f64::NEG_INFINITY {
+                continue;
             }
+            let (pos, left_id, cost, beta) = (node.pos, node.left_id, node.cost, node.beta);
+            let mut prevs = std::mem::take(&mut self.prevs);
+            prevs.clear();
+            prevs.extend(self.prev_nodes(pos));
+            for &j in prevs.iter() {
+                let prev = &mut self.nodes[j];
+                let score = beta
+                    - theta
+                        * (matrix.get_trans_cost(prev.right_id as u16, left_id as u16) + cost)
+                            as f64;
+                prev.beta = log_sum_exp([prev.beta, score].into_iter());
+            }
+            self.prevs = prevs;
         }
 
-        paths
+        let z = self.nodes[eos].alpha;
+        for node in self.nodes.iter_mut() {
+            node.prob = (node.alpha + node.beta - z).exp();
+        }
     }
-}
 
-#[derive(Debug)]
-struct BackwardPath {
-    cost_from_bos: i32,
-    cost_from_eos: i32,
-    back_path: Vec<Rc<Node>>,
-}
+    // EOS is the last node
+    fn eos(&self) -> usize {
+        let eos = self.nodes.len() - 1;
+        assert!(self.nodes[eos].is_eos());
+        eos
+    }
 
-impl BackwardPath {
-    pub fn new(node: Rc<Node>, right_path: Option<&BackwardPath>, matrix: &Matrix) -> BackwardPath {
-        let cost_from_bos = node.min_cost;
-        let mut cost_from_eos = 0;
-        let mut back_path: Vec<Rc<Node>> = Vec::new();
-
-        if let Some(base_path) = right_path {
-            let neighbor_node = &base_path.back_path[&base_path.back_path.len() - 1];
-            cost_from_eos = base_path.cost_from_eos
-                + neighbor_node.cost
-                + matrix.get_trans_cost(node.right_id as u16, neighbor_node.left_id as u16);
-            // copy base_path to back_path
-            for node in base_path.back_path.iter() {
-                back_path.push(Rc::clone(&node));
-            }
-        } else {
-            assert!(&node.is_eos());
+    // indexes of the nodes of the best path from BOS to EOS into path
+    pub fn backward(&self, path: &mut Vec<usize>) {
+        path.clear();
+        let mut index = self.eos();
+        while index != NO_NODE {
+            path.push(index);
+            index = self.nodes[index].back;
         }
+        path.reverse();
+    }
 
-        back_path.push(Rc::clone(&node));
+    /// The nodes after BOS of the indexes given by backward() or NBestSearch::next(),
+    /// with their previous nodes in the path.
+    pub fn path_nodes<'a>(
+        &'a self,
+        path: &'a [usize],
+    ) -> impl Iterator<Item = (&'a Node, &'a Node)> {
+        path.windows(2)
+            .map(|w| (&self.nodes[w[0]], &self.nodes[w[1]]))
+    }
 
-        BackwardPath {
-            cost_from_bos,
-            cost_from_eos,
-            back_path,
-        }
+    // no nodes after reset()
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
     }
 
-    fn total_cost(&self) -> i32 {
-        self.cost_from_bos + self.cost_from_eos
+    // number of the nodes which can be added without the allocation
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+}
+
+// log(sum(exp(x))) without the overflow
+fn log_sum_exp(values: impl Iterator<Item = f64>) -> f64 {
+    let mut max = f64::NEG_INFINITY;
+    let mut sum = 0.0;
+    for v in values.filter(|&v| v != f64::NEG_INFINITY) {
+        if v > max {
+            sum = sum * (max - v).exp() + 1.0;
+            max = v;
+        } else {
+            sum += (v - max).exp();
+        }
     }
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + sum.ln()
+}
 
-    fn is_complete(&self) -> bool {
-        self.back_path[&self.back_path.len() - 1].is_bos()
+/// A* search of the paths from EOS to BOS in the order of the total cost,
+/// which can be reused for the next lattice.
+#[derive(Debug, Default)]
+pub struct NBestSearch {
+    // (node index, next path index toward EOS), the paths share the suffixes
+    paths: Vec<(usize, usize)>,
+    queue: BinaryHeap<BackwardPath>,
+}
+
+impl NBestSearch {
+    // start the search on the lattice after end()
+    pub fn start(&mut self, lattice: &Lattice) {
+        self.paths.clear();
+        self.queue.clear();
+        self.paths.push((lattice.eos(), NO_NODE));
+        self.queue.push(BackwardPath {
+            cost_from_bos: lattice.nodes[lattice.eos()].min_cost,
+            cost_from_eos: 0,
+            path: 0,
+        });
     }
 
-    #[allow(dead_code)]
-    fn print_path(&self) {
-        // for debug
-        println!("total_cost={}", self.total_cost());
-        for node in self.back_path.iter() {
+    // indexes of the nodes of the next best path from BOS to EOS into path, and the total cost
+    pub fn next(
+        &mut self,
+        lattice: &Lattice,
+        matrix: &Matrix,
+        path: &mut Vec<usize>,
+    ) -> Option<i32> {
+        while let Some(bp) = self.queue.pop() {
+            let node = &lattice.nodes[self.paths[bp.path].0];
             if node.is_bos() {
-                println!("\tBOS");
-            } else if node.is_eos() {
-                println!("\tEOS");
-            } else {
-                println!(
-                    "\t{}\t{}",
-                    unsafe {
-                        str::from_utf8(slice::from_raw_parts(node.original_ptr, node.original_len))
-                            .unwrap()
-                    },
-                    unsafe {
-                        str::from_utf8(slice::from_raw_parts(node.feature_ptr, node.feature_len))
-                            .unwrap()
+                path.clear();
+                let mut next_path = bp.path;
+                while next_path != NO_NODE {
+                    let (index, next) = self.paths[next_path];
+                    path.push(index);
+                    next_path = next;
+                }
+                return Some(bp.cost_from_eos);
+            }
+
+            let enodes = &lattice.enodes[node.pos as usize];
+            for (k, &i) in enodes.iter().enumerate() {
+                let enode = &lattice.nodes[i];
+                if enode.skip {
+                    // the nodes before SPACE, once for each start position
+                    if enodes[..k]
+                        .iter()
+                        .any(|&j| lattice.nodes[j].skip && lattice.nodes[j].pos == enode.pos)
+                    {
+                        continue;
+                    }
+                    for &j in lattice.enodes[enode.pos as usize].iter() {
+                        self.push(lattice, matrix, j, &bp);
                     }
-                );
+                } else {
+                    self.push(lattice, matrix, i, &bp);
+                }
             }
         }
+        None
+    }
+
+    // extend the path to the previous node
+    fn push(
+        &mut self,
+        lattice: &Lattice,
+        matrix: &Matrix,
+        index: usize,
+        right_path: &BackwardPath,
+    ) {
+        let node = &lattice.nodes[index];
+        let neighbor_node = &lattice.nodes[self.paths[right_path.path].0];
+        let cost_from_eos = right_path.cost_from_eos
+            + neighbor_node.cost
+            + matrix.get_trans_cost(node.right_id as u16, neighbor_node.left_id as u16);
+        self.paths.push((index, right_path.path));
+        self.queue.push(BackwardPath {
+            cost_from_bos: node.min_cost,
+            cost_from_eos,
+            path: self.paths.len() - 1,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct BackwardPath {
+    // best cost from BOS to the first node, as the heuristic
+    cost_from_bos: i32,
+    cost_from_eos: i32,
+    // index of NBestSearch::paths
+    path: usize,
+}
+
+impl BackwardPath {
+    fn total_cost(&self) -> i32 {
+        self.cost_from_bos + self.cost_from_eos
     }
 }
 
@@ -361,3 +510,88 @@ impl PartialEq for BackwardPath {
         self.total_cost() == other.total_cost()
     }
 }
+
+#[cfg(test)]
+use super::tests::{fixture_dicdir, fixture_rcfile, fixture_tokenizer};
+#[cfg(test)]
+use super::{Error, tokenizer};
+
+#[test]
+fn test_marginal() {
+    let tokenizer = tokenizer::TokenizerBuilder::new()
+        .mecabrc(fixture_dicdir().join("mecabrc").to_str().unwrap())
+        .marginal(true)
+        .build()
+        .unwrap();
+    assert!(tokenizer.marginal());
+    assert_eq!(tokenizer.theta(), 0.75);
+
+    // the probabilities of the paths are exp(-theta * cost / cost_factor)
+    let t = tokenizer.theta() / tokenizer.cost_factor() as f64;
+    for s in ["東京都", "すもももも"] {
+        let paths: Vec<_> = tokenizer.n_best(s).collect();
+        let scores: Vec<f64> = paths
+            .iter()
+            .map(|(_, cost)| (-t * *cost as f64).exp())
+            .collect();
+        let z: f64 = scores.iter().sum();
+        let tokens = tokenizer.parse(s);
+        assert_eq!(tokens, paths[0].0);
+        for token in tokens.iter() {
+            let prob: f64 = paths
+                .iter()
+                .zip(scores.iter())
+                .filter(|((path, _), _)| {
+                    path.iter().any(|t| {
+                        (t.start, t.end, t.posid, &t.feature)
+                            == (token.start, token.end, token.posid, &token.feature)
+                    })
+                })
+                .map(|(_, score)| score / z)
+                .sum();
+            assert!((token.prob - prob).abs() < 1e-9, "{:?} {}", token, prob);
+        }
+    }
+    let min_prob = |tokenizer: &tokenizer::Tokenizer| {
+        tokenizer
+            .parse("すもももも")
+            .iter()
+            .map(|t| t.prob)
+            .fold(1.0, f64::min)
+    };
+    let p0 = min_prob(&tokenizer);
+    assert!(p0 > 0.5 && p0 < 1.0, "{}", p0);
+
+    // probabilities in (0, 1] with spaces and unknown words
+    for token in tokenizer.parse(" 東京 abc すもも ") {
+        assert!(token.prob > 0.0 && token.prob <= 1.0 + 1e-9, "{:?}", token);
+    }
+
+    // without marginal
+    let mut tokenizer = fixture_tokenizer();
+    assert!(tokenizer.parse("すもももも").iter().all(|t| t.prob == 0.0));
+    tokenizer.set_marginal(true);
+    assert_eq!(min_prob(&tokenizer), p0);
+
+    // a higher theta makes the best path sharper
+    tokenizer.set_theta(2.0).unwrap();
+    assert!(min_prob(&tokenizer) > p0);
+
+    // theta must be finite and not negative
+    for theta in [f64::NAN, f64::INFINITY, -1.0] {
+        assert!(matches!(
+            tokenizer.set_theta(theta),
+            Err(Error::InvalidValue { name, .. }) if name == "theta"
+        ));
+    }
+    assert_eq!(tokenizer.theta(), 2.0);
+
+    // theta in mecabrc and the builder
+    let rcfile = fixture_rcfile("marginal", "theta = 0.5\n");
+    let builder = || tokenizer::TokenizerBuilder::new().mecabrc(rcfile.to_str().unwrap());
+    assert_eq!(builder().build().unwrap().theta(), 0.5);
+    assert_eq!(builder().theta(1.5).build().unwrap().theta(), 1.5);
+    assert!(builder().theta(f64::NAN).build().is_err());
+
+    std::fs::remove_file(rcfile).unwrap();
+}
@@ -21,10 +21,22 @@
 *OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 *SOFTWARE.
 */
+mod constraints;
+mod csv;
 mod dic;
+pub mod dict_index;
+mod double_array;
+mod error;
+pub mod format;
 mod lattice;
-mod mecabrc;
+pub mod mecabrc;
 pub mod tokenizer;
+mod user_dic;
+
+pub use constraints::{Boundary, BoundaryConstraints};
+pub use dic::{DicData, DicType, DictionaryInfo};
+pub use error::Error;
+pub use user_dic::{UserDictionary, UserWord};
 
 #[cfg(test)]
 mod tests;
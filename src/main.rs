@@ -21,15 +21,280 @@
 *OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 *SOFTWARE.
 */
+use awabi::BoundaryConstraints;
+use awabi::dict_index;
+use awabi::format::{self, Format};
 use awabi::tokenizer;
-use clap::{Arg, Command};
-use std::io::{self, Read};
+use clap::{Arg, ArgAction, Command};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::process;
 
-fn print_tokens(tokens: &Vec<(String, String)>) {
-    for t in tokens.iter() {
-        println!("{}\t{}", t.0, t.1);
+fn print_dictionary_info(tokenizer: &tokenizer::Tokenizer) {
+    for info in tokenizer.dictionary_info().iter() {
+        println!("filename:\t{}", info.filename);
+        println!("version:\t{}", info.version);
+        println!("charset:\t{}", info.charset);
+        println!("type:\t{}", info.dictype as u32);
+        println!("size:\t{}", info.size);
+        println!("left size:\t{}", info.lsize);
+        println!("right size:\t{}", info.rsize);
+        println!();
     }
-    println!("EOS");
+}
+
+enum Output {
+    Format(Format),
+    Json,
+    JsonLines,
+}
+
+// a sentence of the input, with the constraints in --partial mode
+struct Sentence {
+    text: String,
+    constraints: Option<BoundaryConstraints>,
+}
+
+impl Sentence {
+    // lines of MeCab's partial parsing format
+    fn from_partial(lines: &[String]) -> Sentence {
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        let (text, constraints) = BoundaryConstraints::from_partial(&lines);
+        Sentence {
+            text,
+            constraints: Some(constraints),
+        }
+    }
+}
+
+// N-best tokens and the costs
+fn analyze_paths(
+    tokenizer: &tokenizer::Tokenizer,
+    workspace: &mut tokenizer::Workspace,
+    sentence: &Sentence,
+    nbest: u32,
+) -> Vec<(Vec<tokenizer::Token>, i32)> {
+    let s = &sentence.text;
+    if nbest == 1 {
+        let tokens = match &sentence.constraints {
+            Some(constraints) => tokenizer.parse_with_constraints_with(workspace, s, constraints),
+            None => tokenizer.parse_with(workspace, s),
+        };
+        let cost = tokenizer.path_cost(&tokens);
+        vec![(tokens, cost)]
+    } else {
+        match &sentence.constraints {
+            Some(constraints) => tokenizer.n_best_with_constraints_with(workspace, s, constraints),
+            None => tokenizer.n_best_with(workspace, s),
+        }
+        .take(nbest as usize)
+        .collect()
+    }
+}
+
+// what is written for each sentence
+#[derive(Clone, Copy)]
+enum Mode {
+    // N-best paths, 1 for the best path
+    NBest(u32),
+    // all the words in the lattice with --all-morphs
+    AllMorphs,
+}
+
+// result of a sentence
+fn analyze(
+    tokenizer: &tokenizer::Tokenizer,
+    workspace: &mut tokenizer::Workspace,
+    output: &Output,
+    mode: Mode,
+    sentence: &Sentence,
+) -> String {
+    let s = &sentence.text;
+    let mut out = String::new();
+    let nbest = match mode {
+        Mode::NBest(nbest) => nbest,
+        Mode::AllMorphs => {
+            let morphs = match &sentence.constraints {
+                Some(constraints) => {
+                    tokenizer.all_morphs_with_constraints_with(workspace, s, constraints)
+                }
+                None => tokenizer.all_morphs_with(workspace, s),
+            };
+            match output {
+                Output::Format(format) => format.write_all_morphs(&mut out, s, &morphs),
+                Output::Json | Output::JsonLines => {
+                    format::write_json_all_morphs(&mut out, s, &morphs)
+                }
+            }
+            return out;
+        }
+    };
+    let paths = analyze_paths(tokenizer, workspace, sentence, nbest);
+    match output {
+        Output::Format(format) => {
+            for (tokens, _) in paths.iter() {
+                format.write(&mut out, s, tokens);
+            }
+            if nbest > 1 {
+                format.write_eon(&mut out, s);
+            }
+        }
+        Output::Json | Output::JsonLines => {
+            if nbest == 1 {
+                format::write_json(&mut out, s, &paths[0].0, paths[0].1);
+            } else {
+                format::write_json_n_best(&mut out, s, &paths);
+            }
+        }
+    }
+    out
+}
+
+// lines read at once for each thread with --jobs,
+// fewer lines are analyzed when no more input is available yet
+const LINES_PER_JOB: usize = 256;
+
+// analyze the input files (or stdin) line by line, and write the results to output_path (or stdout)
+fn run(
+    tokenizer: &tokenizer::Tokenizer,
+    output: &Output,
+    mode: Mode,
+    jobs: usize,
+    partial: bool,
+    inputs: &[&str],
+    output_path: Option<&str>,
+) -> Result<(), awabi::Error> {
+    let io_error = |path: &str| {
+        let path = path.to_string();
+        move |source: io::Error| awabi::Error::Io { path, source }
+    };
+    let output_name = output_path.unwrap_or("stdout");
+    let mut writer: Box<dyn Write> = match output_path {
+        Some(path) => Box::new(BufWriter::new(File::create(path).map_err(io_error(path))?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let inputs = if inputs.is_empty() { &["-"] } else { inputs };
+    // a line at a time without --jobs, to write the result immediately
+    let batch_size = if jobs > 1 { jobs * LINES_PER_JOB } else { 1 };
+
+    let mut workspace = tokenizer::Workspace::new();
+    let mut count = 0;
+    let mut write_results = |writer: &mut Box<dyn Write>, sentences: &[Sentence]| {
+        let results: Vec<String> = if jobs > 1 {
+            tokenizer.batch_with_jobs(sentences, jobs, |workspace, s| {
+                analyze(tokenizer, workspace, output, mode, s)
+            })
+        } else {
+            sentences
+                .iter()
+                .map(|s| analyze(tokenizer, &mut workspace, output, mode, s))
+                .collect()
+        };
+        for result in results.iter() {
+            // a JSON array, or a JSON object for each line
+            if let Output::Json = output {
+                writer.write_all(if count == 0 { b"[\n" } else { b",\n" })?;
+            }
+            writer.write_all(result.as_bytes())?;
+            if let Output::JsonLines = output {
+                writer.write_all(b"\n")?;
+            }
+            count += 1;
+        }
+        writer.flush()
+    };
+
+    let mut sentences: Vec<Sentence> = Vec::with_capacity(batch_size);
+    let mut partial_lines: Vec<String> = Vec::new();
+    let mut line: Vec<u8> = Vec::new();
+    for &input in inputs.iter() {
+        let input_name = if input == "-" { "stdin" } else { input };
+        let mut reader: BufReader<Box<dyn Read>> = if input == "-" {
+            BufReader::new(Box::new(io::stdin()))
+        } else {
+            BufReader::new(Box::new(File::open(input).map_err(io_error(input))?))
+        };
+        loop {
+            line.clear();
+            if reader
+                .read_until(b'\n', &mut line)
+                .map_err(io_error(input_name))?
+                == 0
+            {
+                break;
+            }
+            // LF or CRLF
+            if line.last() == Some(&b'\n') {
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+            }
+            let line = String::from_utf8_lossy(&line).into_owned();
+            if partial {
+                // a sentence of the lines until EOS like MeCab, empty lines are skipped
+                if line != "EOS" {
+                    if !line.is_empty() {
+                        partial_lines.push(line);
+                    }
+                    continue;
+                }
+                if partial_lines.is_empty() {
+                    continue;
+                }
+                sentences.push(Sentence::from_partial(&partial_lines));
+                partial_lines.clear();
+            } else {
+                sentences.push(Sentence {
+                    text: line,
+                    constraints: None,
+                });
+            }
+            // the next read may wait for the input, e.g. interactive use
+            if sentences.len() == batch_size || reader.buffer().is_empty() {
+                write_results(&mut writer, &sentences).map_err(io_error(output_name))?;
+                sentences.clear();
+            }
+        }
+        // without EOS at the end of the file
+        if !partial_lines.is_empty() {
+            sentences.push(Sentence::from_partial(&partial_lines));
+            partial_lines.clear();
+        }
+    }
+    write_results(&mut writer, &sentences).map_err(io_error(output_name))?;
+    if let Output::Json = output {
+        let end: &[u8] = if count == 0 { b"[]\n" } else { b"\n]\n" };
+        writer.write_all(end).map_err(io_error(output_name))?;
+    }
+    writer.flush().map_err(io_error(output_name))
+}
+
+// formats of mecabrc overridden by the options
+fn output_format(
+    matches: &clap::ArgMatches,
+    tokenizer: &tokenizer::Tokenizer,
+) -> Result<Format, awabi::Error> {
+    let output_format_type = matches
+        .get_one::<String>("output-format-type")
+        .map(|s| s.as_str());
+    let mut format = Format::from_mecabrc(tokenizer.mecabrc(), output_format_type)?;
+    if let Some(node_format) = matches.get_one::<String>("node-format") {
+        format = format.node_format(node_format)?;
+    }
+    if let Some(unk_format) = matches.get_one::<String>("unk-format") {
+        format = format.unk_format(unk_format)?;
+    }
+    if let Some(bos_format) = matches.get_one::<String>("bos-format") {
+        format = format.bos_format(bos_format)?;
+    }
+    if let Some(eos_format) = matches.get_one::<String>("eos-format") {
+        format = format.eos_format(eos_format)?;
+    }
+    if let Some(eon_format) = matches.get_one::<String>("eon-format") {
+        format = format.eon_format(eon_format)?;
+    }
+    Ok(format)
 }
 
 fn main() {
@@ -41,37 +306,260 @@ fn main() {
                 .long("nbest")
                 .value_name("COUNT"),
         )
+        .arg(
+            Arg::new("all-morphs")
+                .help("output all morphs in the lattice")
+                .short('a')
+                .long("all-morphs")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("rcfile")
                 .help("use FILE as resource file")
                 .short('r')
                 .long("rcfile")
                 .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("dicdir")
+                .help("set DIR as the system dictionary directory")
+                .short('d')
+                .long("dicdir")
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::new("userdic")
+                .help("use FILE as the user dictionary, can be repeated")
+                .short('u')
+                .long("userdic")
+                .value_name("FILE")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("output-format-type")
+                .help("set output format type (wakati, yomi, chasen, simple, dump, json, jsonl or node-format-TYPE in mecabrc)")
+                .short('O')
+                .long("output-format-type")
+                .value_name("TYPE"),
+        )
+        .arg(
+            Arg::new("partial")
+                .help("partial parsing mode, a sentence is the lines until EOS")
+                .short('p')
+                .long("partial")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("marginal")
+                .help("output marginal probabilities (%pP in the node format)")
+                .short('m')
+                .long("marginal")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("theta")
+                .help("set the temperature of the marginal probabilities")
+                .short('t')
+                .long("theta")
+                .value_name("FLOAT")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            Arg::new("jobs")
+                .help("analyze lines on N threads, the output is in the input order and written when no more input is available yet or after N*256 lines")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("output")
+                .help("set the output file name")
+                .short('o')
+                .long("output")
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::new("input")
+                .help("input files, stdin if not given or -")
+                .value_name("FILE")
+                .num_args(1..),
+        )
+        .arg(
+            Arg::new("node-format")
+                .help("use STR as the user-defined node format")
+                .short('F')
+                .long("node-format")
+                .value_name("STR"),
+        )
+        .arg(
+            Arg::new("unk-format")
+                .help("use STR as the user-defined unknown node format")
+                .short('U')
+                .long("unk-format")
+                .value_name("STR"),
+        )
+        .arg(
+            Arg::new("bos-format")
+                .help("use STR as the user-defined beginning-of-sentence format")
+                .short('B')
+                .long("bos-format")
+                .value_name("STR"),
+        )
+        .arg(
+            Arg::new("eos-format")
+                .help("use STR as the user-defined end-of-sentence format")
+                .short('E')
+                .long("eos-format")
+                .value_name("STR"),
+        )
+        .arg(
+            Arg::new("eon-format")
+                .help("use STR as the user-defined end-of-NBest format")
+                .short('S')
+                .long("eon-format")
+                .value_name("STR"),
+        )
+        .arg(
+            Arg::new("dictionary-info")
+                .help("show dictionary information and exit")
+                .short('D')
+                .long("dictionary-info")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("dict-index")
+                .about("compile a source dictionary like mecab-dict-index")
+                .arg(
+                    Arg::new("dicdir")
+                        .help("set DIR as the source dictionary directory")
+                        .short('d')
+                        .long("dicdir")
+                        .value_name("DIR")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("outdir")
+                        .help("set DIR as the output directory")
+                        .short('o')
+                        .long("outdir")
+                        .value_name("DIR")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("userdic")
+                        .help("build user dictionary FILE from CSV files")
+                        .short('u')
+                        .long("userdic")
+                        .value_name("FILE")
+                        .requires("csv"),
+                )
+                .arg(
+                    Arg::new("csv")
+                        .help("user dictionary source files, compiled with -u")
+                        .value_name("CSV")
+                        .num_args(1..)
+                        .requires("userdic"),
+                ),
         );
 
     let matches = app.get_matches();
-    let nbest = if let Some(n_best) = matches.get_one::<String>("nbest") {
-        n_best.parse().unwrap()
+    if let Some(matches) = matches.subcommand_matches("dict-index") {
+        let dicdir = matches.get_one::<String>("dicdir").unwrap();
+        let outdir = matches.get_one::<String>("outdir").unwrap();
+        let result = if let Some(userdic) = matches.get_one::<String>("userdic") {
+            let csv_paths: Vec<&str> = matches
+                .get_many::<String>("csv")
+                .unwrap()
+                .map(|s| s.as_str())
+                .collect();
+            dict_index::compile_user_dic(dicdir, &csv_paths, userdic)
+        } else {
+            dict_index::compile(dicdir, outdir)
+        };
+        if let Err(e) = result {
+            eprintln!("awabi: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mode = if matches.get_flag("all-morphs") {
+        Mode::AllMorphs
+    } else if let Some(n_best) = matches.get_one::<String>("nbest") {
+        Mode::NBest(n_best.parse().unwrap())
     } else {
-        1
+        Mode::NBest(1)
     };
-    let rcfile = if let Some(rcfile_option) = matches.get_one::<String>("rcfile") {
-        Some(rcfile_option.as_str())
-    } else {
-        None
+    let mut builder = tokenizer::TokenizerBuilder::new();
+    if let Some(rcfile) = matches.get_one::<String>("rcfile") {
+        builder = builder.mecabrc(rcfile);
+    }
+    if let Some(dicdir) = matches.get_one::<String>("dicdir") {
+        builder = builder.dicdir(dicdir);
+    }
+    for userdic in matches.get_many::<String>("userdic").into_iter().flatten() {
+        builder = builder.userdic(userdic);
+    }
+    builder = builder.marginal(matches.get_flag("marginal"));
+    if let Some(theta) = matches.get_one::<f64>("theta") {
+        builder = builder.theta(*theta);
+    }
+
+    let tokenizer = match builder.build() {
+        Ok(tokenizer) => tokenizer,
+        Err(e) => {
+            eprintln!("awabi: {}", e);
+            process::exit(1);
+        }
     };
+    if matches.get_flag("dictionary-info") {
+        print_dictionary_info(&tokenizer);
+        return;
+    }
 
-    let mut lines = String::new();
-    io::stdin().read_to_string(&mut lines).unwrap();
-    lines = lines.trim_end().to_string();
+    // -O json or -O jsonl
+    let output = match matches
+        .get_one::<String>("output-format-type")
+        .map(|s| s.as_str())
+    {
+        Some("json") => Output::Json,
+        Some("jsonl") => Output::JsonLines,
+        _ => match output_format(&matches, &tokenizer) {
+            Ok(format) => Output::Format(format),
+            Err(e) => {
+                eprintln!("awabi: {}", e);
+                process::exit(1);
+            }
+        },
+    };
 
-    let tokenizer = tokenizer::Tokenizer::new(rcfile).unwrap();
-    for s in lines.split("\n") {
-        if nbest == 1 {
-            print_tokens(&tokenizer.tokenize(s));
-        } else {
-            for tokens in tokenizer.tokenize_n_best(s, nbest).iter() {
-                print_tokens(tokens);
+    let inputs: Vec<&str> = matches
+        .get_many::<String>("input")
+        .into_iter()
+        .flatten()
+        .map(|s| s.as_str())
+        .collect();
+    let jobs = matches
+        .get_one::<usize>("jobs")
+        .copied()
+        .unwrap_or(1)
+        .max(1);
+    if let Err(e) = run(
+        &tokenizer,
+        &output,
+        mode,
+        jobs,
+        matches.get_flag("partial"),
+        &inputs,
+        matches.get_one::<String>("output").map(|s| s.as_str()),
+    ) {
+        match e {
+            // e.g. awabi | head
+            awabi::Error::Io { source, .. } if source.kind() == io::ErrorKind::BrokenPipe => {}
+            e => {
+                eprintln!("awabi: {}", e);
+                process::exit(1);
             }
         }
     }
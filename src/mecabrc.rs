@@ -21,47 +21,253 @@
 *OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 *SOFTWARE.
 */
+//! mecabrc (and dicrc) settings.
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs;
 use std::io;
-use std::io::BufReader;
-use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use regex::Regex;
+use super::Error;
 
+/// Find mecabrc in the order of
+///
+/// 1. `$MECABRC` (even if it does not exist)
+/// 2. `~/.mecabrc`
+/// 3. `etc/mecabrc` under `$CONDA_PREFIX`, `~/.nix-profile`, `/usr/local`,
+///    `/opt/homebrew`, `/opt/local`, `/run/current-system/sw` and `/usr`
+/// 4. `/etc/mecabrc`
 pub fn find_mecabrc() -> Option<String> {
-    for path in vec!["/usr/local/etc/mecabrc", "/etc/mecabrc"] {
-        if Path::new(path).exists() {
-            return Some(path.to_string());
-        }
+    find_mecabrc_by(|name| std::env::var(name).ok().filter(|s| !s.is_empty()))
+}
+
+// find_mecabrc() with the environment variables by var
+fn find_mecabrc_by(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    if let Some(path) = var("MECABRC") {
+        return Some(path);
+    }
+
+    let home = var("HOME");
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    if let Some(home) = &home {
+        candidates.push(Path::new(home).join(".mecabrc"));
     }
-    None
+    let mut prefixes: Vec<PathBuf> = Vec::new();
+    if let Some(conda_prefix) = var("CONDA_PREFIX") {
+        prefixes.push(PathBuf::from(conda_prefix));
+    }
+    if let Some(home) = &home {
+        prefixes.push(Path::new(home).join(".nix-profile"));
+    }
+    for prefix in [
+        "/usr/local",
+        "/opt/homebrew",
+        "/opt/local",
+        "/run/current-system/sw",
+        "/usr",
+    ] {
+        prefixes.push(PathBuf::from(prefix));
+    }
+    for prefix in prefixes.iter() {
+        candidates.push(prefix.join("etc/mecabrc"));
+    }
+    candidates.push(PathBuf::from("/etc/mecabrc"));
+
+    candidates
+        .into_iter()
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().to_string())
 }
 
-pub fn rc_map(path: &str) -> Result<HashMap<String, String>, io::Error> {
-    let mut rc: HashMap<String, String> = HashMap::new();
-    let re = Regex::new(r"^(\S+)\s*=\s*(\S+)").unwrap();
+/// Settings of a mecabrc file.
+///
+/// The settings in `dicrc` of dicdir are merged by `load_dicrc()`,
+/// and the ones in mecabrc take precedence over them.
+///
+/// Each line is `name = value`, and the lines starting with `;` or `#` are comments.
+/// The value keeps the trailing spaces like MeCab.
+/// The first value wins if a name appears more than once.
+/// `$(rcpath)` in dicdir and userdic is replaced with the directory of the file,
+/// and relative paths of them are resolved from the directory.
+#[derive(Debug, Clone, Default)]
+pub struct Mecabrc {
+    map: HashMap<String, String>,
+}
+
+impl Mecabrc {
+    pub fn open(path: &str) -> Result<Mecabrc, Error> {
+        let mut rc = Mecabrc::default();
+        rc.load(path)?;
+
+        let rcpath = Path::new(path).parent().unwrap_or(Path::new(""));
+        if let Some(dicdir) = rc.map.get_mut("dicdir") {
+            *dicdir = resolve_path(rcpath, dicdir.trim());
+        }
+        if let Some(userdic) = rc.map.get_mut("userdic") {
+            *userdic = userdic
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| resolve_path(rcpath, s))
+                .collect::<Vec<_>>()
+                .join(",");
+        }
+        Ok(rc)
+    }
+
+    // add the settings in the file which are not set yet
+    fn load(&mut self, path: &str) -> Result<(), Error> {
+        let text = fs::read_to_string(path).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => Error::InvalidUtf8 {
+                path: path.to_string(),
+            },
+            _ => Error::io(path, e),
+        })?;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_start();
+            if line.trim_end().is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            // trailing spaces are a part of the value (e.g. "node-format-wakati = %m ")
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim_start()),
+                _ => {
+                    return Err(Error::InvalidFormat {
+                        path: path.to_string(),
+                        line: i + 1,
+                        reason: "expected 'name = value'".to_string(),
+                    });
+                }
+            };
+            self.map
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+        Ok(())
+    }
+
+    /// Add the settings in dicrc of dicdir which are not set yet.
+    pub fn load_dicrc(&mut self) -> Result<(), Error> {
+        if let Some(dicdir) = self.dicdir() {
+            let path = Path::new(dicdir).join("dicrc");
+            if path.is_file() {
+                self.load(&path.to_string_lossy())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// raw value of the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.map.get(name).map(|s| s.as_str())
+    }
+
+    /// Set the value, overwriting the one from the file.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.map.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn dicdir(&self) -> Option<&str> {
+        self.get("dicdir")
+    }
+
+    /// path of the file in dicdir
+    pub fn dic_path(&self, filename: &str) -> Result<String, Error> {
+        get_dic_path(&self.map, filename)
+    }
+
+    /// paths of the comma separated userdic
+    pub fn userdics(&self) -> Vec<&str> {
+        match self.get("userdic") {
+            Some(userdic) => userdic.split(',').filter(|s| !s.is_empty()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn output_format_type(&self) -> Option<&str> {
+        self.get("output-format-type")
+    }
+
+    /// node-format, or node-format-NAME with the name
+    pub fn node_format(&self, name: Option<&str>) -> Option<&str> {
+        self.format("node-format", name)
+    }
+
+    /// bos-format, or bos-format-NAME with the name
+    pub fn bos_format(&self, name: Option<&str>) -> Option<&str> {
+        self.format("bos-format", name)
+    }
+
+    /// eos-format, or eos-format-NAME with the name
+    pub fn eos_format(&self, name: Option<&str>) -> Option<&str> {
+        self.format("eos-format", name)
+    }
+
+    /// unk-format, or unk-format-NAME with the name
+    pub fn unk_format(&self, name: Option<&str>) -> Option<&str> {
+        self.format("unk-format", name)
+    }
 
-    let f = File::open(path)?;
-    let reader = BufReader::new(f);
+    /// eon-format, or eon-format-NAME with the name
+    pub fn eon_format(&self, name: Option<&str>) -> Option<&str> {
+        self.format("eon-format", name)
+    }
 
-    for line in reader.lines() {
-        let line = line.unwrap();
-        if let Some(caps) = re.captures(&line) {
-            rc.insert(caps[1].to_string(), caps[2].to_string());
+    fn format(&self, key: &str, name: Option<&str>) -> Option<&str> {
+        match name {
+            Some(name) => self.get(&format!("{}-{}", key, name)),
+            None => self.get(key),
         }
     }
 
-    Ok(rc)
+    pub fn cost_factor(&self) -> Result<Option<i32>, Error> {
+        self.parse("cost-factor")
+    }
+
+    pub fn max_grouping_size(&self) -> Result<Option<u32>, Error> {
+        self.parse("max-grouping-size")
+    }
+
+    pub fn theta(&self) -> Result<Option<f64>, Error> {
+        self.parse("theta")
+    }
+
+    fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, Error> {
+        match self.get(name) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| Error::InvalidValue {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+// expand $(rcpath) and make the path relative to rcpath
+fn resolve_path(rcpath: &Path, path: &str) -> String {
+    let path = path.replace("$(rcpath)", &rcpath.to_string_lossy());
+    if Path::new(&path).is_relative() {
+        rcpath.join(&path).to_string_lossy().to_string()
+    } else {
+        path
+    }
+}
+
+pub fn rc_map(path: &str) -> Result<HashMap<String, String>, Error> {
+    Ok(Mecabrc::open(path)?.map)
 }
 
-pub fn get_dic_path(rc_map: &HashMap<String, String>, filename: &str) -> String {
-    let dirname = &rc_map[&String::from("dicdir")];
+pub fn get_dic_path(rc_map: &HashMap<String, String>, filename: &str) -> Result<String, Error> {
+    let dirname = rc_map.get("dicdir").ok_or(Error::DicdirNotFound)?;
     let mut s = String::from(dirname);
-    s.push_str("/");
+    s.push('/');
     s.push_str(filename);
-    s
+    Ok(s)
 }
 
 #[test]
@@ -69,3 +275,142 @@ fn test_mecabrc() {
     let rc_map = rc_map(&find_mecabrc().unwrap()).unwrap();
     assert_ne!(rc_map.get(&String::from("dicdir")), None);
 }
+
+#[test]
+fn test_mecabrc_error() {
+    assert!(matches!(
+        rc_map("/something/wrong/path/mecabrc"),
+        Err(Error::Io { .. })
+    ));
+    assert!(matches!(
+        get_dic_path(&HashMap::new(), "sys.dic"),
+        Err(Error::DicdirNotFound)
+    ));
+}
+
+#[test]
+fn test_find_mecabrc() {
+    let dir = std::env::temp_dir().join(format!("awabi-find-mecabrc-{}", std::process::id()));
+    let home = dir.join("home");
+    let conda = dir.join("conda");
+    fs::create_dir_all(home.join(".nix-profile/etc")).unwrap();
+    fs::create_dir_all(conda.join("etc")).unwrap();
+    let var = |mecabrc: Option<&str>| {
+        let home = home.to_str().unwrap().to_string();
+        let conda = conda.to_str().unwrap().to_string();
+        let mecabrc = mecabrc.map(|s| s.to_string());
+        move |name: &str| match name {
+            "MECABRC" => mecabrc.clone(),
+            "HOME" => Some(home.clone()),
+            "CONDA_PREFIX" => Some(conda.clone()),
+            _ => None,
+        }
+    };
+    let path = |p: &PathBuf| Some(p.to_str().unwrap().to_string());
+
+    let system = find_mecabrc_by(|_| None);
+    assert_eq!(find_mecabrc_by(var(None)), system);
+
+    fs::write(home.join(".nix-profile/etc/mecabrc"), "").unwrap();
+    assert_eq!(
+        find_mecabrc_by(var(None)),
+        path(&home.join(".nix-profile/etc/mecabrc"))
+    );
+    fs::write(conda.join("etc/mecabrc"), "").unwrap();
+    assert_eq!(find_mecabrc_by(var(None)), path(&conda.join("etc/mecabrc")));
+    fs::write(home.join(".mecabrc"), "").unwrap();
+    assert_eq!(find_mecabrc_by(var(None)), path(&home.join(".mecabrc")));
+    assert_eq!(
+        find_mecabrc_by(var(Some("/nothing/mecabrc"))),
+        Some("/nothing/mecabrc".to_string())
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_mecabrc_parser() {
+    let dir = std::env::temp_dir().join(format!("awabi-mecabrc-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("mecabrc");
+    let path = path.to_str().unwrap();
+    let dir = dir.to_str().unwrap();
+
+    fs::write(
+        path,
+        "; comment\n\
+         # comment = not a value\n\
+         \n\
+         dicdir =  $(rcpath)/dic  \r\n\
+         userdic = user1.dic, /abs/user2.dic,,\n\
+         node-format-foo = %m %H\\n\n\
+         node-format-bar = %m \n\
+         node-format=%m\\t%H\\n\n\
+         eos-format-foo = EOS\\n\n\
+         output-format-type = foo\n\
+         cost-factor = 800\n\
+         max-grouping-size=10\n\
+         theta = 0.5\n\
+         cost-factor = 900\n",
+    )
+    .unwrap();
+    let rc = Mecabrc::open(path).unwrap();
+    assert_eq!(rc.dicdir(), Some(format!("{}/dic", dir).as_str()));
+    assert_eq!(
+        rc.dic_path("sys.dic").unwrap(),
+        format!("{}/dic/sys.dic", dir)
+    );
+    assert_eq!(
+        rc.userdics(),
+        vec![format!("{}/user1.dic", dir).as_str(), "/abs/user2.dic"]
+    );
+    assert_eq!(rc.node_format(Some("foo")), Some("%m %H\\n"));
+    assert_eq!(rc.node_format(None), Some("%m\\t%H\\n"));
+    assert_eq!(rc.node_format(Some("bar")), Some("%m "));
+    assert_eq!(rc.eos_format(Some("foo")), Some("EOS\\n"));
+    assert_eq!(rc.bos_format(Some("foo")), None);
+    assert_eq!(rc.output_format_type(), Some("foo"));
+    assert_eq!(rc.cost_factor().unwrap(), Some(800));
+    assert_eq!(rc.max_grouping_size().unwrap(), Some(10));
+    assert_eq!(rc.theta().unwrap(), Some(0.5));
+    assert_eq!(rc.get("comment"), None);
+
+    // relative dicdir
+    fs::write(path, "dicdir = dic\n").unwrap();
+    let rc = Mecabrc::open(path).unwrap();
+    assert_eq!(rc.dicdir(), Some(format!("{}/dic", dir).as_str()));
+    assert_eq!(rc.cost_factor().unwrap(), None);
+    assert!(rc.userdics().is_empty());
+
+    // dicrc
+    fs::create_dir_all(format!("{}/dic", dir)).unwrap();
+    fs::write(
+        format!("{}/dic/dicrc", dir),
+        "cost-factor = 800\nmax-grouping-size = 5\ndicdir = /other\n",
+    )
+    .unwrap();
+    fs::write(path, "dicdir = dic\nmax-grouping-size = 10\n").unwrap();
+    let mut rc = Mecabrc::open(path).unwrap();
+    rc.load_dicrc().unwrap();
+    assert_eq!(rc.dicdir(), Some(format!("{}/dic", dir).as_str()));
+    assert_eq!(rc.cost_factor().unwrap(), Some(800));
+    assert_eq!(rc.max_grouping_size().unwrap(), Some(10));
+
+    fs::write(path, "dicdir = /dic\nmax-grouping-size = -1\n").unwrap();
+    assert!(matches!(
+        Mecabrc::open(path).unwrap().max_grouping_size(),
+        Err(Error::InvalidValue { .. })
+    ));
+    fs::write(path, "dicdir = /dic\nno value\n").unwrap();
+    assert!(matches!(
+        Mecabrc::open(path),
+        Err(Error::InvalidFormat { line: 2, .. })
+    ));
+    fs::write(path, "= value\n").unwrap();
+    assert!(matches!(
+        Mecabrc::open(path),
+        Err(Error::InvalidFormat { line: 1, .. })
+    ));
+
+    fs::remove_dir_all(dir).unwrap();
+}
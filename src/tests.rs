@@ -137,3 +137,173 @@ fn test_tokenize_n_best() {
         3
     );
 }
+
+#[test]
+fn test_parse() {
+    let tokenizer = tokenizer::Tokenizer::new(None).unwrap();
+
+    let tokens = tokenizer.parse("すもももももももものうち");
+    assert_eq!(tokens.len(), 7);
+    assert_eq!(tokens[0].surface, "すもも");
+    assert_eq!(tokens[0].feature, "名詞,一般,*,*,*,*,すもも,スモモ,スモモ");
+    assert_eq!((tokens[0].start, tokens[0].end), (0, 9));
+    assert_eq!((tokens[0].char_start, tokens[0].char_end), (0, 3));
+    assert_eq!(tokens[6].surface, "うち");
+    assert_eq!((tokens[6].start, tokens[6].end), (30, 36));
+    assert_eq!((tokens[6].char_start, tokens[6].char_end), (10, 12));
+    for token in tokens.iter() {
+        assert_ne!(token.left_id, 0);
+        assert_ne!(token.right_id, 0);
+    }
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|t| (t.surface.clone(), t.feature.clone()))
+            .collect::<Vec<_>>(),
+        tokenizer.tokenize("すもももももももものうち")
+    );
+
+    let tokens_list = tokenizer.parse_n_best("すもももももももものうち", 3);
+    assert_eq!(tokens_list.len(), 3);
+    assert_eq!(tokens_list[0], tokens);
+}
+
+// the surfaces are at the byte and char offsets of the tokens
+pub(crate) fn assert_offsets(s: &str, tokens: &[tokenizer::Token]) {
+    let chars: Vec<char> = s.chars().collect();
+    for token in tokens.iter() {
+        assert_eq!(&s[token.byte_range()], token.surface);
+        assert_eq!(
+            chars[token.char_range()].iter().collect::<String>(),
+            token.surface
+        );
+    }
+}
+
+#[test]
+fn test_offsets_with_spaces() {
+    let tokenizer = tokenizer::Tokenizer::new(None).unwrap();
+
+    // leading spaces
+    let s = "  すもも";
+    let tokens = tokenizer.parse(s);
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].byte_range(), 2..11);
+    assert_eq!(tokens[0].char_range(), 2..5);
+    assert_offsets(s, &tokens);
+
+    // trailing spaces
+    let s = "すもも \t";
+    let tokens = tokenizer.parse(s);
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].byte_range(), 0..9);
+    assert_eq!(tokens[0].char_range(), 0..3);
+    assert_offsets(s, &tokens);
+
+    // interior spaces
+    let s = "すもも  も もも";
+    let tokens = tokenizer.parse(s);
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|t| (t.surface.as_str(), t.byte_range(), t.char_range()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("すもも", 0..9, 0..3),
+            ("も", 11..14, 5..6),
+            ("もも", 15..21, 7..9)
+        ]
+    );
+    assert_offsets(s, &tokens);
+
+    let s = "山嵐は might is right という英語を引いて説諭を加えた";
+    assert_offsets(s, &tokenizer.parse(s));
+    for tokens in tokenizer.parse_n_best(s, 3).iter() {
+        assert_offsets(s, tokens);
+    }
+
+    assert_eq!(tokenizer.parse(" ").len(), 0);
+}
+
+#[test]
+fn test_tokenizer_error() {
+    assert!(matches!(
+        tokenizer::Tokenizer::new(Some("/something/wrong/path/mecabrc")),
+        Err(Error::Io { .. })
+    ));
+}
+
+#[test]
+fn test_dictionary_info() {
+    let tokenizer = tokenizer::Tokenizer::new(None).unwrap();
+    let infos = tokenizer.dictionary_info();
+
+    assert_eq!(infos[0].dictype, DicType::Sys);
+    assert!(infos[0].filename.ends_with("sys.dic"));
+    assert_eq!(infos[infos.len() - 1].dictype, DicType::Unk);
+    assert!(infos[infos.len() - 1].filename.ends_with("unk.dic"));
+    for info in infos.iter() {
+        assert_eq!(info.version, 102);
+        assert_eq!(info.lsize, infos[0].lsize);
+        assert_eq!(info.rsize, infos[0].rsize);
+    }
+}
+
+// user dictionaries compiled with tests/dic by fixture_dicdir()
+const FIXTURE_USER_DICS: &[(&str, &str)] = &[
+    (
+        "product",
+        "あわび,1,1,1000,名詞,固有名詞,組織,*,*,*,あわび,アワビ,アワビ\n",
+    ),
+    (
+        "people",
+        "すももも,1,1,1000,名詞,固有名詞,人名,一般,*,*,すももも,スモモモ,スモモモ\n\
+         あわび,1,1,1000,名詞,固有名詞,人名,一般,*,*,あわび,アワビ,アワビ\n",
+    ),
+];
+
+// tests/dic compiled once for all the tests, with product.dic, people.dic
+// and mecabrc which has only the dicdir
+pub(crate) fn fixture_dicdir() -> &'static std::path::Path {
+    static DICDIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+    DICDIR.get_or_init(|| {
+        let srcdir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/dic");
+        let outdir = std::env::temp_dir().join(format!("awabi-fixture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&outdir);
+        dict_index::compile(srcdir.to_str().unwrap(), outdir.to_str().unwrap()).unwrap();
+        for (name, csv) in FIXTURE_USER_DICS.iter() {
+            let csv_path = outdir.join(format!("{}.csv", name));
+            std::fs::write(&csv_path, csv).unwrap();
+            dict_index::compile_user_dic(
+                srcdir.to_str().unwrap(),
+                &[csv_path.to_str().unwrap()],
+                outdir.join(format!("{}.dic", name)).to_str().unwrap(),
+            )
+            .unwrap();
+        }
+        std::fs::write(
+            outdir.join("mecabrc"),
+            format!("dicdir = {}\n", outdir.to_str().unwrap()),
+        )
+        .unwrap();
+        outdir
+    })
+}
+
+// tokenizer of fixture_dicdir() without user dictionaries
+pub(crate) fn fixture_tokenizer() -> tokenizer::Tokenizer {
+    let rcfile = fixture_dicdir().join("mecabrc");
+    tokenizer::Tokenizer::new(rcfile.to_str()).unwrap()
+}
+
+// mecabrc of fixture_dicdir() followed by rc in a temporary file
+pub(crate) fn fixture_rcfile(name: &str, rc: &str) -> std::path::PathBuf {
+    let rcfile =
+        std::env::temp_dir().join(format!("awabi-{}-{}.mecabrc", name, std::process::id()));
+    std::fs::write(
+        &rcfile,
+        format!("dicdir = {}\n{}", fixture_dicdir().to_str().unwrap(), rc),
+    )
+    .unwrap();
+    rcfile
+}
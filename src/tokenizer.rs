@@ -22,13 +22,221 @@
 *SOFTWARE.
 */
 use super::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+
+/// A morpheme of the analysis result.
+#[derive(Debug, Clone)]
+pub struct Token {
+    /// surface string
+    pub surface: String,
+    /// comma separated features (part of speech, reading, ...)
+    pub feature: String,
+    /// left context id
+    pub left_id: u16,
+    /// right context id
+    pub right_id: u16,
+    /// part of speech id
+    pub posid: u16,
+    /// word cost
+    pub wcost: i16,
+    /// cost of the best path from BOS to this token including the word cost,
+    /// which can be less than the cost along the path in N-best results
+    pub cost: i32,
+    /// connection cost from the previous token (BOS for the first token),
+    /// which is the best previous token in `all_morphs()`
+    pub conn_cost: i32,
+    /// byte offsets in the input string
+    pub start: usize,
+    pub end: usize,
+    /// char offsets in the input string
+    pub char_start: usize,
+    pub char_end: usize,
+    /// unknown word which is not in the dictionaries
+    pub unknown: bool,
+    /// marginal probability, 0 unless the tokenizer computes them with `marginal`
+    pub prob: f64,
+}
+
+/// `prob` is compared by the bit pattern to make `Token` `Eq`,
+/// so a `prob` of 0.0 is not equal to -0.0 and NaN is equal to the same NaN.
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.surface == other.surface
+            && self.feature == other.feature
+            && self.left_id == other.left_id
+            && self.right_id == other.right_id
+            && self.posid == other.posid
+            && self.wcost == other.wcost
+            && self.cost == other.cost
+            && self.conn_cost == other.conn_cost
+            && self.start == other.start
+            && self.end == other.end
+            && self.char_start == other.char_start
+            && self.char_end == other.char_end
+            && self.unknown == other.unknown
+            && self.prob.to_bits() == other.prob.to_bits()
+    }
+}
+
+impl Eq for Token {}
+
+impl Token {
+    // make a token of the lattice node which starts at char_start chars of s
+    fn from_node(s: &str, node: &lattice::Node, char_start: usize, conn_cost: i32) -> Token {
+        // lattice positions start at 1
+        let start = (node.pos - 1) as usize;
+        let end = (node.epos - 1) as usize;
+        Token {
+            surface: node.original_to_string(),
+            feature: node.feature_to_string(),
+            left_id: node.left_id as u16,
+            right_id: node.right_id as u16,
+            posid: node.posid,
+            wcost: node.cost as i16,
+            cost: node.min_cost,
+            conn_cost,
+            start,
+            end,
+            char_start,
+            char_end: char_start + s[start..end].chars().count(),
+            unknown: node.unknown,
+            prob: node.prob,
+        }
+    }
+
+    // make tokens from the lattice nodes with their previous nodes
+    // in the order of the start position, EOS is skipped
+    fn from_nodes<'a>(
+        s: &str,
+        nodes: impl IntoIterator<Item = (&'a lattice::Node, &'a lattice::Node)>,
+        matrix: &dic::Matrix,
+    ) -> Vec<Token> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut byte_pos = 0;
+        let mut char_pos = 0;
+        for (prev, node) in nodes {
+            if node.is_eos() {
+                continue;
+            }
+            let start = (node.pos - 1) as usize;
+            char_pos += s[byte_pos..start].chars().count();
+            byte_pos = start;
+            let conn_cost = matrix.get_trans_cost(prev.right_id as u16, node.left_id as u16);
+            tokens.push(Token::from_node(s, node, char_pos, conn_cost));
+        }
+        tokens
+    }
+
+    /// byte range of the surface in the input string
+    pub fn byte_range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    /// char range of the surface in the input string
+    pub fn char_range(&self) -> std::ops::Range<usize> {
+        self.char_start..self.char_end
+    }
+
+    fn into_pair(self) -> (String, String) {
+        (self.surface, self.feature)
+    }
+}
+
+/// Reusable buffers of `Tokenizer::parse_with()` etc.
+///
+/// The lattice and the buffers of the dictionary lookups and the paths are cleared
+/// for each sentence without freeing the memory, so a workspace for each thread
+/// avoids the allocations except for the returned tokens.
+/// The nodes are removed when the results are returned (or `NBest` is dropped),
+/// so the workspace keeps no pointers to the input.
+#[derive(Debug, Default)]
+pub struct Workspace {
+    lattice: lattice::Lattice,
+    n_best: lattice::NBestSearch,
+    // words found at a position, moved to the lattice
+    entries: Vec<dic::DicEntry>,
+    // node indexes of a path
+    path: Vec<usize>,
+}
+
+impl Workspace {
+    pub fn new() -> Workspace {
+        Workspace::default()
+    }
+}
+
+// the workspace of NBest, or the one given by the caller
+enum WorkspaceMut<'a> {
+    Owned(Workspace),
+    Borrowed(&'a mut Workspace),
+}
+
+impl WorkspaceMut<'_> {
+    fn get(&mut self) -> &mut Workspace {
+        match self {
+            WorkspaceMut::Owned(workspace) => workspace,
+            WorkspaceMut::Borrowed(workspace) => workspace,
+        }
+    }
+}
+
+/// Iterator of the N-best results returned by `Tokenizer::n_best()`.
+///
+/// Each item is the tokens and the total cost from BOS to EOS.
+pub struct NBest<'a> {
+    tokenizer: &'a Tokenizer,
+    s: &'a str,
+    // the nodes point to user_dictionary
+    _user_dictionary: Arc<UserDictionary>,
+    workspace: WorkspaceMut<'a>,
+}
+
+// the lattice of a borrowed workspace must not keep the nodes
+impl Drop for NBest<'_> {
+    fn drop(&mut self) {
+        self.workspace.get().lattice.reset();
+    }
+}
+
+impl Iterator for NBest<'_> {
+    type Item = (Vec<Token>, i32);
+
+    fn next(&mut self) -> Option<(Vec<Token>, i32)> {
+        let Workspace {
+            lattice,
+            n_best,
+            path,
+            ..
+        } = self.workspace.get();
+        let cost = n_best.next(lattice, &self.tokenizer.matrix, path)?;
+        Some((
+            Token::from_nodes(self.s, lattice.path_nodes(path), &self.tokenizer.matrix),
+            cost,
+        ))
+    }
+}
+
+const DEFAULT_COST_FACTOR: i32 = 700;
+const DEFAULT_THETA: f64 = 0.75;
 
 #[derive(Clone)]
 pub struct Tokenizer {
     // system dictionary
     sys_dic: dic::MeCabDic,
-    // user dictionary
-    user_dic: Option<dic::MeCabDic>,
+    // user dictionaries in priority order
+    user_dics: Vec<dic::MeCabDic>,
+    // runtime user dictionary, shared with the clones
+    user_dictionary: Arc<RwLock<Arc<UserDictionary>>>,
+    // contexts of the parts of speech found by user_word(), shared with the clones
+    contexts: Arc<Mutex<HashMap<String, Option<dic::Context>>>>,
+    cost_factor: i32,
+    // marginal probabilities with the temperature
+    marginal: bool,
+    theta: f64,
+    // settings of mecabrc and dicrc
+    rc: mecabrc::Mecabrc,
 
     // for unknown chars
     char_property: dic::CharProperty,
@@ -39,104 +247,1095 @@ pub struct Tokenizer {
 }
 
 impl Tokenizer {
-    pub fn new(mecabrc_path: Option<&str>) -> Result<Tokenizer, std::io::Error> {
-        let path = if let Some(s) = mecabrc_path {
-            s.to_string()
-        } else {
-            mecabrc::find_mecabrc().expect("Can't find mecabrc")
-        };
-
-        let rc_map = mecabrc::rc_map(&path)?;
-
-        let sys_dic = dic::MeCabDic::open(&mecabrc::get_dic_path(&rc_map, "sys.dic")).unwrap();
-        let user_dic: Option<dic::MeCabDic> = if let Some(userdic_path) = rc_map.get("userdic") {
-            Some(dic::MeCabDic::open(userdic_path).unwrap())
-        } else {
-            None
-        };
-
-        let char_property =
-            dic::CharProperty::open(&mecabrc::get_dic_path(&rc_map, "char.bin")).unwrap();
-        let unk_dic = dic::MeCabDic::open(&mecabrc::get_dic_path(&rc_map, "unk.dic")).unwrap();
+    pub fn new(mecabrc_path: Option<&str>) -> Result<Tokenizer, Error> {
+        let mut builder = TokenizerBuilder::new();
+        if let Some(path) = mecabrc_path {
+            builder = builder.mecabrc(path);
+        }
+        builder.build()
+    }
 
-        let matrix = dic::Matrix::open(&mecabrc::get_dic_path(&rc_map, "matrix.bin")).unwrap();
+    /// Make a tokenizer from the contents of sys.dic, unk.dic, char.bin and matrix.bin.
+    ///
+    /// They can be embedded with `include_bytes!` instead of installed files.
+    pub fn from_bytes(
+        sys_dic: impl Into<DicData>,
+        unk_dic: impl Into<DicData>,
+        char_bin: impl Into<DicData>,
+        matrix_bin: impl Into<DicData>,
+    ) -> Result<Tokenizer, Error> {
+        Tokenizer::from_dics(
+            dic::MeCabDic::from_data("sys.dic", sys_dic.into(), dic::DicType::Sys)?,
+            dic::CharProperty::from_data("char.bin", char_bin.into())?,
+            dic::MeCabDic::from_data("unk.dic", unk_dic.into(), dic::DicType::Unk)?,
+            dic::Matrix::from_data("matrix.bin", matrix_bin.into())?,
+        )
+    }
 
+    fn from_dics(
+        sys_dic: dic::MeCabDic,
+        char_property: dic::CharProperty,
+        unk_dic: dic::MeCabDic,
+        matrix: dic::Matrix,
+    ) -> Result<Tokenizer, Error> {
+        sys_dic.check_matrix(&matrix)?;
+        unk_dic.check_matrix(&matrix)?;
         Ok(Tokenizer {
             sys_dic,
-            user_dic,
+            user_dics: Vec::new(),
+            cost_factor: DEFAULT_COST_FACTOR,
+            marginal: false,
+            theta: DEFAULT_THETA,
+            rc: mecabrc::Mecabrc::default(),
+            user_dictionary: Arc::new(RwLock::new(Arc::new(UserDictionary::new()))),
+            contexts: Arc::new(Mutex::new(HashMap::new())),
             char_property,
             unk_dic,
             matrix,
         })
     }
 
-    fn build_lattice(&self, s: &str) -> lattice::Lattice {
-        let s = s.as_bytes();
-        let mut lattice = lattice::Lattice::new(s.len());
+    /// Add a user dictionary with a lower priority than the already added ones.
+    ///
+    /// All user dictionaries contribute words to the lattice,
+    /// and the earlier one wins when the costs are the same.
+    pub fn add_user_dic(&mut self, path: &str) -> Result<(), Error> {
+        let user_dic = dic::MeCabDic::open(path, dic::DicType::Usr)?;
+        self.push_user_dic(user_dic)
+    }
+
+    /// Add a user dictionary from its contents like `add_user_dic()`.
+    pub fn add_user_dic_bytes(&mut self, user_dic: impl Into<DicData>) -> Result<(), Error> {
+        let user_dic = dic::MeCabDic::from_data("user.dic", user_dic.into(), dic::DicType::Usr)?;
+        self.push_user_dic(user_dic)
+    }
+
+    fn push_user_dic(&mut self, user_dic: dic::MeCabDic) -> Result<(), Error> {
+        user_dic.check_matrix(&self.matrix)?;
+        self.user_dics.push(user_dic);
+        Ok(())
+    }
+
+    /// Replace the runtime user dictionary which has the highest priority.
+    ///
+    /// The tokenizer and its clones use it from the next parse,
+    /// and parses in progress keep the old one.
+    /// Returns `Error::InvalidValue` and keeps the current one
+    /// if a context id of the words is out of matrix.bin.
+    pub fn set_user_dictionary(&self, user_dictionary: UserDictionary) -> Result<(), Error> {
+        user_dictionary.check_matrix(&self.matrix)?;
+        *self
+            .user_dictionary
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(user_dictionary);
+        Ok(())
+    }
+
+    /// The current runtime user dictionary.
+    pub fn user_dictionary(&self) -> Arc<UserDictionary> {
+        Arc::clone(
+            &self
+                .user_dictionary
+                .read()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Make a user word of the part of speech (e.g. "名詞,固有名詞,組織").
+    ///
+    /// The context ids are taken from a word of the system dictionary which has the part of speech,
+    /// and the cost is the lowest one of those words.
+    /// The rest of the features are filled with "*".
+    /// Returns None if no word has the part of speech.
+    /// The system dictionary is searched once for each part of speech.
+    pub fn user_word(&self, surface: &str, pos: &str) -> Option<UserWord> {
+        let (left_id, right_id, cost, columns) = *self
+            .contexts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(pos.to_string())
+            .or_insert_with(|| self.sys_dic.find_context(pos))
+            .as_ref()?;
+        let mut feature = pos.to_string();
+        for _ in pos.split(',').count()..columns {
+            feature.push_str(",*");
+        }
+        Some(UserWord::new(surface, left_id, right_id, cost, &feature))
+    }
+
+    /// Settings of mecabrc and dicrc which the tokenizer is made from.
+    pub fn mecabrc(&self) -> &mecabrc::Mecabrc {
+        &self.rc
+    }
+
+    /// cost-factor option of mecabrc or dicrc
+    pub fn cost_factor(&self) -> i32 {
+        self.cost_factor
+    }
+
+    /// Compute the marginal probabilities of the tokens (`Token::prob`) or not, like MeCab's -m.
+    pub fn set_marginal(&mut self, marginal: bool) {
+        self.marginal = marginal;
+    }
+
+    pub fn marginal(&self) -> bool {
+        self.marginal
+    }
+
+    /// Temperature of the marginal probabilities, the scores are exp(-theta * cost / cost_factor).
+    ///
+    /// Returns `Error::InvalidValue` if theta is negative, infinite or NaN.
+    pub fn set_theta(&mut self, theta: f64) -> Result<(), Error> {
+        if !theta.is_finite() || theta < 0.0 {
+            return Err(Error::InvalidValue {
+                name: "theta".to_string(),
+                value: theta.to_string(),
+            });
+        }
+        self.theta = theta;
+        Ok(())
+    }
+
+    pub fn theta(&self) -> f64 {
+        self.theta
+    }
+
+    /// Header information of the system, user and unknown word dictionaries.
+    pub fn dictionary_info(&self) -> Vec<&DictionaryInfo> {
+        let mut infos = vec![&self.sys_dic.info];
+        infos.extend(self.user_dics.iter().map(|user_dic| &user_dic.info));
+        infos.push(&self.unk_dic.info);
+        infos
+    }
+
+    // the nodes point to user_dictionary, so it must outlive the lattice
+    fn build_lattice(
+        &self,
+        workspace: &mut Workspace,
+        text: &str,
+        user_dictionary: &UserDictionary,
+        constraints: Option<&BoundaryConstraints>,
+    ) {
+        // move the entries which satisfy the constraints to the lattice
+        let add_entries =
+            |lattice: &mut lattice::Lattice, entries: &mut Vec<dic::DicEntry>, pos: usize| {
+                let mut added = false;
+                for entry in entries.drain(..) {
+                    if let Some(constraints) = constraints
+                        && !constraints.allows(pos, pos + entry.original_len, entry.feature_bytes())
+                    {
+                        continue;
+                    }
+                    lattice.add(lattice::Node::new(entry), &self.matrix);
+                    added = true;
+                }
+                added
+            };
+
+        let Workspace {
+            lattice, entries, ..
+        } = workspace;
+        let s = text.as_bytes();
+        lattice.clear(s.len());
         let mut pos = 0;
         while pos < s.len() {
             let mut matched: bool = false;
 
-            // user_dic
-            if let Some(user_dic) = &self.user_dic {
-                let user_entries = user_dic.lookup(&s[pos..]);
-                if user_entries.len() > 0 {
-                    for entry in user_entries.into_iter() {
-                        lattice.add(lattice::Node::new(entry), &self.matrix);
-                    }
-                    matched = true;
-                }
+            // user_dictionary
+            user_dictionary.lookup_into(&s[pos..], entries);
+            matched |= add_entries(lattice, entries, pos);
+
+            // user_dics
+            for user_dic in self.user_dics.iter() {
+                user_dic.lookup_into(&s[pos..], entries);
+                matched |= add_entries(lattice, entries, pos);
             }
 
             // sys_dic
-            let sys_entries = self.sys_dic.lookup(&s[pos..]);
-            if sys_entries.len() > 0 {
-                for entry in sys_entries.into_iter() {
-                    lattice.add(lattice::Node::new(entry), &self.matrix);
-                }
+            self.sys_dic.lookup_into(&s[pos..], entries);
+            matched |= add_entries(lattice, entries, pos);
+
+            // unknown
+            let invoke = self
+                .unk_dic
+                .lookup_unknowns_into(&s[pos..], &self.char_property, entries);
+            let mut fallback = entries.first().cloned();
+            if (invoke || !matched) && add_entries(lattice, entries, pos) {
                 matched = true;
             }
+            entries.clear();
 
-            // unknown
-            let (unk_entries, invoke) =
-                self.unk_dic.lookup_unknowns(&s[pos..], &self.char_property);
-            if invoke || !matched {
-                for entry in unk_entries.into_iter() {
-                    lattice.add(lattice::Node::new(entry), &self.matrix);
+            // an unknown word to the next possible boundary if the constraints reject all
+            if let Some(constraints) = constraints
+                && !matched
+                && let Some(mut entry) = fallback.take()
+            {
+                entry.original_len = constraints.next_end(text, pos) - pos;
+                if let Some(pattern) = constraints.feature_at(pos) {
+                    entry.feature_ptr = pattern.as_ptr();
+                    entry.feature_len = pattern.len();
                 }
+                entry.skip = false;
+                lattice.add(lattice::Node::new(entry), &self.matrix);
             }
 
             pos += lattice.forward();
         }
         lattice.end(&self.matrix);
-        lattice
+        if self.marginal {
+            lattice.forward_backward(self.theta / self.cost_factor.max(1) as f64, &self.matrix);
+        }
     }
 
-    pub fn tokenize(&self, s: &str) -> Vec<(String, String)> {
-        let lattice = self.build_lattice(s);
-        let nodes = lattice.backward();
-        let mut entries: Vec<(String, String)> = Vec::new();
-        assert!(nodes[0].is_bos());
-        assert!(nodes[nodes.len() - 1].is_eos());
-        for i in 1..nodes.len() - 1 {
-            entries.push((nodes[i].original_to_string(), nodes[i].feature_to_string()));
+    pub fn parse(&self, s: &str) -> Vec<Token> {
+        self.parse_with(&mut Workspace::new(), s)
+    }
+
+    /// `parse()` reusing the lattice of the workspace.
+    pub fn parse_with(&self, workspace: &mut Workspace, s: &str) -> Vec<Token> {
+        self.best_path(workspace, s, None)
+    }
+
+    /// `parse()` with the tokens which satisfy the constraints.
+    pub fn parse_with_constraints(&self, s: &str, constraints: &BoundaryConstraints) -> Vec<Token> {
+        self.best_path(&mut Workspace::new(), s, Some(constraints))
+    }
+
+    /// `parse_with_constraints()` reusing the lattice of the workspace.
+    pub fn parse_with_constraints_with(
+        &self,
+        workspace: &mut Workspace,
+        s: &str,
+        constraints: &BoundaryConstraints,
+    ) -> Vec<Token> {
+        self.best_path(workspace, s, Some(constraints))
+    }
+
+    fn best_path(
+        &self,
+        workspace: &mut Workspace,
+        s: &str,
+        constraints: Option<&BoundaryConstraints>,
+    ) -> Vec<Token> {
+        let user_dictionary = self.user_dictionary();
+        self.build_lattice(workspace, s, &user_dictionary, constraints);
+        let Workspace { lattice, path, .. } = workspace;
+        lattice.backward(path);
+        let tokens = Token::from_nodes(s, lattice.path_nodes(path), &self.matrix);
+        lattice.reset();
+        tokens
+    }
+
+    pub fn parse_n_best(&self, s: &str, n: u32) -> Vec<Vec<Token>> {
+        self.parse_n_best_with(&mut Workspace::new(), s, n)
+    }
+
+    /// `parse_n_best()` reusing the lattice of the workspace.
+    pub fn parse_n_best_with(&self, workspace: &mut Workspace, s: &str, n: u32) -> Vec<Vec<Token>> {
+        self.n_best_with(workspace, s)
+            .take(n as usize)
+            .map(|(tokens, _)| tokens)
+            .collect()
+    }
+
+    /// Iterator of the results and their total costs in the order of the cost.
+    ///
+    /// The results are searched lazily, so the iteration can be stopped at any time.
+    pub fn n_best<'a>(&'a self, s: &'a str) -> NBest<'a> {
+        self.n_best_workspace(WorkspaceMut::Owned(Workspace::new()), s, None)
+    }
+
+    /// `n_best()` reusing the lattice of the workspace.
+    pub fn n_best_with<'a>(&'a self, workspace: &'a mut Workspace, s: &'a str) -> NBest<'a> {
+        self.n_best_workspace(WorkspaceMut::Borrowed(workspace), s, None)
+    }
+
+    /// `n_best()` with the tokens which satisfy the constraints.
+    pub fn n_best_with_constraints<'a>(
+        &'a self,
+        s: &'a str,
+        constraints: &'a BoundaryConstraints,
+    ) -> NBest<'a> {
+        self.n_best_workspace(WorkspaceMut::Owned(Workspace::new()), s, Some(constraints))
+    }
+
+    /// `n_best_with_constraints()` reusing the lattice of the workspace.
+    pub fn n_best_with_constraints_with<'a>(
+        &'a self,
+        workspace: &'a mut Workspace,
+        s: &'a str,
+        constraints: &'a BoundaryConstraints,
+    ) -> NBest<'a> {
+        self.n_best_workspace(WorkspaceMut::Borrowed(workspace), s, Some(constraints))
+    }
+
+    // the nodes may point to the feature patterns of constraints
+    fn n_best_workspace<'a>(
+        &'a self,
+        mut workspace: WorkspaceMut<'a>,
+        s: &'a str,
+        constraints: Option<&'a BoundaryConstraints>,
+    ) -> NBest<'a> {
+        let user_dictionary = self.user_dictionary();
+        self.build_lattice(workspace.get(), s, &user_dictionary, constraints);
+        let Workspace {
+            lattice, n_best, ..
+        } = workspace.get();
+        n_best.start(lattice);
+        NBest {
+            tokenizer: self,
+            s,
+            _user_dictionary: user_dictionary,
+            workspace,
+        }
+    }
+
+    /// All the words in the lattice grouped by the start position, like MeCab's -a.
+    ///
+    /// `Token::cost` is the cost of the best path from BOS to the word.
+    pub fn all_morphs(&self, s: &str) -> Vec<Vec<Token>> {
+        self.all_morphs_with(&mut Workspace::new(), s)
+    }
+
+    /// `all_morphs()` reusing the lattice of the workspace.
+    pub fn all_morphs_with(&self, workspace: &mut Workspace, s: &str) -> Vec<Vec<Token>> {
+        self.lattice_tokens(workspace, s, None)
+    }
+
+    /// `all_morphs()` with the words which satisfy the constraints.
+    pub fn all_morphs_with_constraints(
+        &self,
+        s: &str,
+        constraints: &BoundaryConstraints,
+    ) -> Vec<Vec<Token>> {
+        self.lattice_tokens(&mut Workspace::new(), s, Some(constraints))
+    }
+
+    /// `all_morphs_with_constraints()` reusing the lattice of the workspace.
+    pub fn all_morphs_with_constraints_with(
+        &self,
+        workspace: &mut Workspace,
+        s: &str,
+        constraints: &BoundaryConstraints,
+    ) -> Vec<Vec<Token>> {
+        self.lattice_tokens(workspace, s, Some(constraints))
+    }
+
+    fn lattice_tokens(
+        &self,
+        workspace: &mut Workspace,
+        s: &str,
+        constraints: Option<&BoundaryConstraints>,
+    ) -> Vec<Vec<Token>> {
+        let user_dictionary = self.user_dictionary();
+        self.build_lattice(workspace, s, &user_dictionary, constraints);
+        let mut groups: Vec<Vec<Token>> = Vec::new();
+        for token in Token::from_nodes(s, workspace.lattice.nodes_with_back(), &self.matrix) {
+            match groups.last_mut() {
+                Some(group) if group[0].start == token.start => group.push(token),
+                _ => groups.push(vec![token]),
+            }
         }
-        entries
+        workspace.lattice.reset();
+        groups
+    }
+
+    /// Total cost of the tokens from BOS to EOS.
+    pub fn path_cost(&self, tokens: &[Token]) -> i32 {
+        // right id of BOS and left id of EOS are 0
+        let mut right_id = 0;
+        let mut cost = 0;
+        for token in tokens.iter() {
+            cost += self.matrix.get_trans_cost(right_id, token.left_id) + token.wcost as i32;
+            right_id = token.right_id;
+        }
+        cost + self.matrix.get_trans_cost(right_id, 0)
+    }
+
+    pub fn tokenize(&self, s: &str) -> Vec<(String, String)> {
+        self.parse(s).into_iter().map(Token::into_pair).collect()
+    }
+
+    /// `tokenize()` with the tokens which satisfy the constraints.
+    ///
+    /// The nodes which cross a forced boundary or split a span of a token are rejected.
+    pub fn tokenize_with_constraints(
+        &self,
+        s: &str,
+        constraints: &BoundaryConstraints,
+    ) -> Vec<(String, String)> {
+        self.parse_with_constraints(s, constraints)
+            .into_iter()
+            .map(Token::into_pair)
+            .collect()
+    }
+
+    /// `tokenize_with_constraints()` reusing the lattice of the workspace.
+    pub fn tokenize_with_constraints_with(
+        &self,
+        workspace: &mut Workspace,
+        s: &str,
+        constraints: &BoundaryConstraints,
+    ) -> Vec<(String, String)> {
+        self.parse_with_constraints_with(workspace, s, constraints)
+            .into_iter()
+            .map(Token::into_pair)
+            .collect()
+    }
+
+    /// `tokenize()` reusing the lattice of the workspace.
+    pub fn tokenize_with(&self, workspace: &mut Workspace, s: &str) -> Vec<(String, String)> {
+        self.parse_with(workspace, s)
+            .into_iter()
+            .map(Token::into_pair)
+            .collect()
     }
 
     pub fn tokenize_n_best(&self, s: &str, n: u32) -> Vec<Vec<(String, String)>> {
-        let lattice = self.build_lattice(s);
-        let nodes_vec = lattice.backward_astar(n, &self.matrix);
-        let mut entries_vec: Vec<Vec<(String, String)>> = Vec::new();
-        for nodes in nodes_vec.iter() {
-            // TODO: convert nodes_vec to entries_vec
-            let mut entries: Vec<(String, String)> = Vec::new();
-            for i in 1..nodes.len() - 1 {
-                entries.push((nodes[i].original_to_string(), nodes[i].feature_to_string()));
+        self.parse_n_best(s, n)
+            .into_iter()
+            .map(|tokens| tokens.into_iter().map(Token::into_pair).collect())
+            .collect()
+    }
+
+    /// `parse()` the sentences on the available threads, the results are in the same order.
+    pub fn parse_batch(&self, sentences: &[&str]) -> Vec<Vec<Token>> {
+        self.batch(sentences, |workspace, s| self.parse_with(workspace, s))
+    }
+
+    /// `tokenize()` the sentences on the available threads, the results are in the same order.
+    pub fn tokenize_batch(&self, sentences: &[&str]) -> Vec<Vec<(String, String)>> {
+        self.batch(sentences, |workspace, s| self.tokenize_with(workspace, s))
+    }
+
+    fn batch<T: Send>(
+        &self,
+        sentences: &[&str],
+        f: impl Fn(&mut Workspace, &str) -> T + Sync,
+    ) -> Vec<T> {
+        let jobs = thread::available_parallelism().map_or(1, |n| n.get());
+        self.batch_with_jobs(sentences, jobs, |workspace, s| f(workspace, s))
+    }
+
+    /// Apply f to the items on `jobs` threads with a workspace for each thread,
+    /// the results are in the same order.
+    ///
+    /// e.g. `tokenizer.batch_with_jobs(&sentences, 4, |workspace, s| tokenizer.parse_with(workspace, s))`
+    pub fn batch_with_jobs<I: Sync, T: Send>(
+        &self,
+        items: &[I],
+        jobs: usize,
+        f: impl Fn(&mut Workspace, &I) -> T + Sync,
+    ) -> Vec<T> {
+        // split the items into a chunk for each thread, and join the results in order
+        let chunk_size = items.len().div_ceil(jobs.max(1)).max(1);
+        if chunk_size >= items.len() {
+            let mut workspace = Workspace::new();
+            return items.iter().map(|item| f(&mut workspace, item)).collect();
+        }
+        let f = &f;
+        thread::scope(|scope| {
+            let handles: Vec<_> = items
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        let mut workspace = Workspace::new();
+                        chunk
+                            .iter()
+                            .map(|item| f(&mut workspace, item))
+                            .collect::<Vec<T>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+}
+
+/// Builder of `Tokenizer` with the dictionary locations and options.
+///
+/// The options which are not given are taken from mecabrc, and then dicrc of dicdir.
+/// mecabrc is searched in the default locations only if neither mecabrc, dicdir
+/// nor all of sys.dic, unk.dic, char.bin and matrix.bin are given.
+#[derive(Debug, Clone, Default)]
+pub struct TokenizerBuilder {
+    mecabrc: Option<String>,
+    dicdir: Option<String>,
+    sys_dic: Option<String>,
+    unk_dic: Option<String>,
+    char_bin: Option<String>,
+    matrix_bin: Option<String>,
+    userdics: Vec<String>,
+    cost_factor: Option<i32>,
+    max_grouping_size: Option<u32>,
+    marginal: bool,
+    theta: Option<f64>,
+}
+
+impl TokenizerBuilder {
+    pub fn new() -> TokenizerBuilder {
+        TokenizerBuilder::default()
+    }
+
+    /// path of mecabrc
+    pub fn mecabrc(mut self, path: &str) -> TokenizerBuilder {
+        self.mecabrc = Some(path.to_string());
+        self
+    }
+
+    /// directory of sys.dic, unk.dic, char.bin and matrix.bin
+    pub fn dicdir(mut self, path: &str) -> TokenizerBuilder {
+        self.dicdir = Some(path.to_string());
+        self
+    }
+
+    /// path of sys.dic instead of the one in dicdir
+    pub fn sys_dic(mut self, path: &str) -> TokenizerBuilder {
+        self.sys_dic = Some(path.to_string());
+        self
+    }
+
+    /// path of unk.dic instead of the one in dicdir
+    pub fn unk_dic(mut self, path: &str) -> TokenizerBuilder {
+        self.unk_dic = Some(path.to_string());
+        self
+    }
+
+    /// path of char.bin instead of the one in dicdir
+    pub fn char_bin(mut self, path: &str) -> TokenizerBuilder {
+        self.char_bin = Some(path.to_string());
+        self
+    }
+
+    /// path of matrix.bin instead of the one in dicdir
+    pub fn matrix_bin(mut self, path: &str) -> TokenizerBuilder {
+        self.matrix_bin = Some(path.to_string());
+        self
+    }
+
+    /// Add a user dictionary after the ones in mecabrc.
+    pub fn userdic(mut self, path: &str) -> TokenizerBuilder {
+        self.userdics.push(path.to_string());
+        self
+    }
+
+    pub fn cost_factor(mut self, cost_factor: i32) -> TokenizerBuilder {
+        self.cost_factor = Some(cost_factor);
+        self
+    }
+
+    /// max length of grouped unknown words
+    pub fn max_grouping_size(mut self, size: u32) -> TokenizerBuilder {
+        self.max_grouping_size = Some(size);
+        self
+    }
+
+    /// Compute the marginal probabilities of the tokens.
+    pub fn marginal(mut self, marginal: bool) -> TokenizerBuilder {
+        self.marginal = marginal;
+        self
+    }
+
+    /// Temperature of the marginal probabilities.
+    pub fn theta(mut self, theta: f64) -> TokenizerBuilder {
+        self.theta = Some(theta);
+        self
+    }
+
+    pub fn build(self) -> Result<Tokenizer, Error> {
+        let all_files = self.sys_dic.is_some()
+            && self.unk_dic.is_some()
+            && self.char_bin.is_some()
+            && self.matrix_bin.is_some();
+        let mecabrc_path = match self.mecabrc {
+            Some(path) => Some(path),
+            None if self.dicdir.is_none() && !all_files => {
+                Some(mecabrc::find_mecabrc().ok_or(Error::MecabrcNotFound)?)
+            }
+            None => None,
+        };
+        let mut rc = match &mecabrc_path {
+            Some(path) => mecabrc::Mecabrc::open(path)?,
+            None => mecabrc::Mecabrc::default(),
+        };
+        if let Some(dicdir) = &self.dicdir {
+            rc.set("dicdir", dicdir);
+        }
+        rc.load_dicrc()?;
+        let dic_path = |path: Option<String>, filename: &str| match path {
+            Some(path) => Ok(path),
+            None => rc.dic_path(filename),
+        };
+
+        let matrix = dic::Matrix::open(&dic_path(self.matrix_bin, "matrix.bin")?)?;
+        let sys_dic = dic::MeCabDic::open(&dic_path(self.sys_dic, "sys.dic")?, dic::DicType::Sys)?;
+        let mut char_property = dic::CharProperty::open(&dic_path(self.char_bin, "char.bin")?)?;
+        let unk_dic = dic::MeCabDic::open(&dic_path(self.unk_dic, "unk.dic")?, dic::DicType::Unk)?;
+
+        if let Some(size) = self.max_grouping_size {
+            char_property.max_grouping_size = size;
+        } else if let Some(size) = rc.max_grouping_size()? {
+            char_property.max_grouping_size = size;
+        }
+
+        let mut tokenizer = Tokenizer::from_dics(sys_dic, char_property, unk_dic, matrix)?;
+        if let Some(cost_factor) = self.cost_factor {
+            tokenizer.cost_factor = cost_factor;
+        } else if let Some(cost_factor) = rc.cost_factor()? {
+            tokenizer.cost_factor = cost_factor;
+        }
+        tokenizer.marginal = self.marginal;
+        if let Some(theta) = self.theta {
+            tokenizer.set_theta(theta)?;
+        } else if let Some(theta) = rc.theta()? {
+            tokenizer.set_theta(theta)?;
+        }
+
+        for userdic_path in rc.userdics() {
+            tokenizer.add_user_dic(userdic_path)?;
+        }
+        for userdic_path in self.userdics.iter() {
+            tokenizer.add_user_dic(userdic_path)?;
+        }
+        tokenizer.rc = rc;
+        Ok(tokenizer)
+    }
+}
+
+#[cfg(test)]
+use super::tests::{assert_offsets, fixture_dicdir, fixture_rcfile, fixture_tokenizer};
+
+#[test]
+fn test_multiple_user_dics() {
+    let dicdir = fixture_dicdir();
+    let path = |name: &str| dicdir.join(name).to_str().unwrap().to_string();
+    let rcfile = fixture_rcfile(
+        "multi-user-dic",
+        &format!(
+            "userdic = {}, {}\n",
+            path("product.dic"),
+            path("people.dic")
+        ),
+    );
+    let tokenizer = Tokenizer::new(rcfile.to_str()).unwrap();
+
+    let infos = tokenizer.dictionary_info();
+    assert_eq!(
+        infos.iter().map(|info| info.dictype).collect::<Vec<_>>(),
+        vec![DicType::Sys, DicType::Usr, DicType::Usr, DicType::Unk]
+    );
+    assert!(infos[1].filename.ends_with("product.dic"));
+    assert!(infos[2].filename.ends_with("people.dic"));
+
+    // entries from each user dictionary, the earlier one wins the tie
+    let tokens = tokenizer.parse("すもももあわび");
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|t| (t.surface.as_str(), t.feature.split(',').nth(2).unwrap()))
+            .collect::<Vec<_>>(),
+        vec![("すももも", "人名"), ("あわび", "組織")]
+    );
+
+    // add_user_dic appends with a lower priority
+    let mut tokenizer = fixture_tokenizer();
+    assert_eq!(tokenizer.parse("すもももあわび")[0].surface, "すもも");
+    tokenizer.add_user_dic(&path("people.dic")).unwrap();
+    tokenizer.add_user_dic(&path("product.dic")).unwrap();
+    let tokens = tokenizer.parse("すもももあわび");
+    assert_eq!(tokens[0].surface, "すももも");
+    assert_eq!(tokens[1].feature.split(',').nth(2).unwrap(), "人名");
+    assert!(matches!(
+        tokenizer.add_user_dic(&path("unk.dic")),
+        Err(Error::CorruptDictionary { .. })
+    ));
+    assert_eq!(tokenizer.dictionary_info().len(), 4);
+
+    std::fs::remove_file(rcfile).unwrap();
+}
+
+#[test]
+fn test_tokenizer_from_bytes() {
+    let dicdir = fixture_dicdir();
+    let read = |name: &str| std::fs::read(dicdir.join(name)).unwrap();
+
+    let mut tokenizer = fixture_tokenizer();
+    tokenizer
+        .add_user_dic(dicdir.join("product.dic").to_str().unwrap())
+        .unwrap();
+    let expected = tokenizer.parse("すもももももももものうちあわび");
+
+    // Vec<u8>, Arc<[u8]> and &'static [u8]
+    let sys_dic: std::sync::Arc<[u8]> = read("sys.dic").into();
+    let char_bin: &'static [u8] = Box::leak(read("char.bin").into_boxed_slice());
+    let mut tokenizer =
+        Tokenizer::from_bytes(sys_dic, read("unk.dic"), char_bin, read("matrix.bin")).unwrap();
+    tokenizer.add_user_dic_bytes(read("product.dic")).unwrap();
+    assert_eq!(tokenizer.parse("すもももももももものうちあわび"), expected);
+    assert_eq!(
+        tokenizer
+            .dictionary_info()
+            .iter()
+            .map(|info| info.filename.as_str())
+            .collect::<Vec<_>>(),
+        vec!["sys.dic", "user.dic", "unk.dic"]
+    );
+
+    // broken data
+    assert!(matches!(
+        Tokenizer::from_bytes(read("unk.dic"), read("unk.dic"), char_bin, read("matrix.bin")),
+        Err(Error::CorruptDictionary { path, .. }) if path == "sys.dic"
+    ));
+    assert!(matches!(
+        Tokenizer::from_bytes(read("sys.dic"), read("unk.dic"), &b"\x00"[..], read("matrix.bin")),
+        Err(Error::CorruptDictionary { path, .. }) if path == "char.bin"
+    ));
+    assert!(matches!(
+        tokenizer.add_user_dic_bytes(Vec::new()),
+        Err(Error::CorruptDictionary { path, .. }) if path == "user.dic"
+    ));
+}
+
+#[test]
+fn test_tokenizer_builder() {
+    let dicdir = fixture_dicdir();
+    let path = |name: &str| dicdir.join(name).to_str().unwrap().to_string();
+    let surfaces = |tokenizer: &Tokenizer, s: &str| {
+        tokenizer
+            .parse(s)
+            .into_iter()
+            .map(|t| t.surface)
+            .collect::<Vec<_>>()
+    };
+
+    // dicdir without mecabrc
+    let tokenizer = TokenizerBuilder::new().dicdir(&path("")).build().unwrap();
+    assert_eq!(tokenizer.dictionary_info().len(), 2);
+    assert_eq!(surfaces(&tokenizer, "すもももあわび")[0], "すもも");
+    // cost-factor in dicrc
+    assert_eq!(tokenizer.cost_factor(), 800);
+
+    // each file and user dictionaries
+    let sys2_dic =
+        std::env::temp_dir().join(format!("awabi-builder-{}-sys2.dic", std::process::id()));
+    let sys2_dic = sys2_dic.to_str().unwrap();
+    std::fs::copy(path("sys.dic"), sys2_dic).unwrap();
+    let tokenizer = TokenizerBuilder::new()
+        .sys_dic(sys2_dic)
+        .unk_dic(&path("unk.dic"))
+        .char_bin(&path("char.bin"))
+        .matrix_bin(&path("matrix.bin"))
+        .userdic(&path("product.dic"))
+        .cost_factor(900)
+        .build()
+        .unwrap();
+    assert!(
+        tokenizer.dictionary_info()[0]
+            .filename
+            .ends_with("sys2.dic")
+    );
+    assert_eq!(
+        surfaces(&tokenizer, "すもももあわび"),
+        vec!["すもも", "も", "あわび"]
+    );
+    assert_eq!(tokenizer.cost_factor(), 900);
+    assert!(matches!(
+        TokenizerBuilder::new()
+            .sys_dic(sys2_dic)
+            .unk_dic(&path("unk.dic"))
+            .char_bin(&path("char.bin"))
+            .matrix_bin(&path("matrix.bin"))
+            .userdic(&path("nothing.dic"))
+            .build(),
+        Err(Error::Io { .. })
+    ));
+
+    // mecabrc options, overridden by the builder
+    let rcfile = fixture_rcfile(
+        "builder",
+        &format!(
+            "userdic = {}\ncost-factor = 900\nmax-grouping-size = 3\n",
+            path("product.dic")
+        ),
+    );
+    let tokenizer = TokenizerBuilder::new()
+        .mecabrc(rcfile.to_str().unwrap())
+        .build()
+        .unwrap();
+    assert_eq!(tokenizer.dictionary_info().len(), 3);
+    assert_eq!(tokenizer.cost_factor(), 900);
+    assert_eq!(surfaces(&tokenizer, "abcdefg"), vec!["a", "b", "c", "defg"]);
+    let tokenizer = TokenizerBuilder::new()
+        .mecabrc(rcfile.to_str().unwrap())
+        .max_grouping_size(24)
+        .userdic(&path("product.dic"))
+        .build()
+        .unwrap();
+    assert_eq!(tokenizer.dictionary_info().len(), 4);
+    assert_eq!(surfaces(&tokenizer, "abcdefg"), vec!["abcdefg"]);
+
+    std::fs::write(&rcfile, format!("dicdir = {}\ncost-factor = x\n", path(""))).unwrap();
+    assert!(matches!(
+        Tokenizer::new(rcfile.to_str()),
+        Err(Error::InvalidValue { name, .. }) if name == "cost-factor"
+    ));
+
+    std::fs::remove_file(rcfile).unwrap();
+    std::fs::remove_file(sys2_dic).unwrap();
+}
+
+#[test]
+fn test_tokenize_batch() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Tokenizer>();
+    assert_send_sync::<Token>();
+    fn assert_send<T: Send>() {}
+    assert_send::<Workspace>();
+
+    let tokenizer = fixture_tokenizer();
+    let sentences: Vec<String> = (0..100)
+        .map(|i| match i % 3 {
+            0 => format!("東京{}", i),
+            1 => "すもももあわび".to_string(),
+            _ => "".to_string(),
+        })
+        .collect();
+    let sentences: Vec<&str> = sentences.iter().map(|s| s.as_str()).collect();
+    let expected: Vec<_> = sentences.iter().map(|s| tokenizer.tokenize(s)).collect();
+    assert_eq!(tokenizer.tokenize_batch(&sentences), expected);
+    let expected: Vec<_> = sentences.iter().map(|s| tokenizer.parse(s)).collect();
+    assert_eq!(tokenizer.parse_batch(&sentences), expected);
+    assert!(tokenizer.tokenize_batch(&[]).is_empty());
+
+    // the number of threads
+    for jobs in [0, 1, 3, 100, 1000] {
+        let results = tokenizer.batch_with_jobs(&sentences, jobs, |workspace, s| {
+            tokenizer.parse_with(workspace, s)
+        });
+        assert_eq!(results, expected);
+    }
+    let empty: &[&str] = &[];
+    assert!(
+        tokenizer
+            .batch_with_jobs(empty, 4, |_, s| s.len())
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_workspace() {
+    let tokenizer = fixture_tokenizer();
+    let mut workspace = Workspace::new();
+    // longer and shorter sentences than the previous ones
+    for s in [
+        "すもももあわび",
+        "東京",
+        "",
+        "すもも も あわび abc 東京 すもももあわび",
+        "abc",
+        " 東京 ",
+    ] {
+        assert_eq!(tokenizer.parse_with(&mut workspace, s), tokenizer.parse(s));
+        assert_eq!(
+            tokenizer.parse_n_best_with(&mut workspace, s, 3),
+            tokenizer.parse_n_best(s, 3)
+        );
+        assert_eq!(
+            tokenizer.tokenize_with(&mut workspace, s),
+            tokenizer.tokenize(s)
+        );
+
+        // with constraints
+        let mut constraints = BoundaryConstraints::new();
+        constraints.boundary(s.len() / 3 * 3);
+        assert_eq!(
+            tokenizer.parse_with_constraints_with(&mut workspace, s, &constraints),
+            tokenizer.parse_with_constraints(s, &constraints)
+        );
+        assert_eq!(
+            tokenizer.tokenize_with_constraints_with(&mut workspace, s, &constraints),
+            tokenizer.tokenize_with_constraints(s, &constraints)
+        );
+        assert_eq!(
+            tokenizer
+                .n_best_with_constraints_with(&mut workspace, s, &constraints)
+                .take(3)
+                .collect::<Vec<_>>(),
+            tokenizer
+                .n_best_with_constraints(s, &constraints)
+                .take(3)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            tokenizer.all_morphs_with_constraints_with(&mut workspace, s, &constraints),
+            tokenizer.all_morphs_with_constraints(s, &constraints)
+        );
+    }
+
+    // no nodes and entries pointing to the input and the dictionaries are kept
+    // after the analysis, but the buffers are kept for the next sentence
+    let no_nodes = |workspace: &Workspace| {
+        workspace.lattice.is_empty()
+            && workspace.lattice.capacity() > 0
+            && workspace.entries.is_empty()
+            && workspace.entries.capacity() > 0
+    };
+    let capacities = |workspace: &Workspace| {
+        (
+            workspace.lattice.capacity(),
+            workspace.entries.capacity(),
+            workspace.path.capacity(),
+        )
+    };
+    let s = String::from("すもももあわび");
+    tokenizer.parse_with(&mut workspace, &s);
+    assert!(no_nodes(&workspace));
+    let kept = capacities(&workspace);
+    tokenizer.parse_with(&mut workspace, &s);
+    assert_eq!(capacities(&workspace), kept);
+    tokenizer.all_morphs_with(&mut workspace, &s);
+    assert!(no_nodes(&workspace));
+    let mut n_best = tokenizer.n_best_with(&mut workspace, &s);
+    n_best.next().unwrap();
+    drop(n_best);
+    assert!(no_nodes(&workspace));
+    drop(s);
+}
+
+#[test]
+fn test_n_best() {
+    let tokenizer = fixture_tokenizer();
+    let mut workspace = Workspace::new();
+    for s in ["すもももあわび", " すもも も あわび ", "東京 abc", ""] {
+        let results: Vec<_> = tokenizer.n_best(s).take(20).collect();
+        assert_eq!(results[0].0, tokenizer.parse(s));
+        for (tokens, cost) in results.iter() {
+            assert_eq!(*cost, tokenizer.path_cost(tokens));
+            // no SPACE
+            assert!(tokens.iter().all(|t| !t.surface.trim().is_empty()));
+            assert_offsets(s, tokens);
+        }
+        assert!(results.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(
+            tokenizer
+                .n_best_with(&mut workspace, s)
+                .take(20)
+                .collect::<Vec<_>>(),
+            results
+        );
+    }
+
+    // all the paths, and stop early
+    let surfaces = |tokens: &[Token]| {
+        tokens
+            .iter()
+            .map(|t| t.surface.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let results: Vec<_> = tokenizer.n_best("東京都").collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(surfaces(&results[0].0), "東 京都");
+    assert_eq!(surfaces(&results[1].0), "東京 都");
+    assert_eq!(tokenizer.parse_n_best("東京都", 10).len(), 2);
+    let mut n_best = tokenizer.n_best("すもももあわび");
+    assert_eq!(n_best.next().unwrap().0.len(), 3);
+}
+
+#[test]
+fn test_all_morphs() {
+    let tokenizer = fixture_tokenizer();
+    let surfaces = |morphs: &[Vec<Token>]| {
+        morphs
+            .iter()
+            .map(|tokens| {
+                tokens
+                    .iter()
+                    .map(|t| t.surface.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let morphs = tokenizer.all_morphs("東京都");
+    assert_eq!(surfaces(&morphs), "東,東京 京都 都");
+    assert!(tokenizer.all_morphs("").is_empty());
+
+    // the tokens of all the paths are in the groups with the best-path costs
+    let mut workspace = Workspace::new();
+    for s in ["すもももあわび", " すもも も あわび ", "東京 abc", "東京都"] {
+        let morphs = tokenizer.all_morphs(s);
+        assert_eq!(tokenizer.all_morphs_with(&mut workspace, s), morphs);
+        assert!(morphs.windows(2).all(|w| w[0][0].start < w[1][0].start));
+        for tokens in morphs.iter() {
+            assert_offsets(s, tokens);
+            assert!(tokens.iter().all(|t| t.start == tokens[0].start));
+            assert!(tokens.iter().all(|t| !t.surface.trim().is_empty()));
+        }
+        let all: Vec<_> = morphs.iter().flatten().collect();
+        for (tokens, _) in tokenizer.n_best(s).take(20) {
+            for token in tokens.iter() {
+                let found: Vec<_> = all
+                    .iter()
+                    .filter(|t| {
+                        (t.start, t.end, &t.feature) == (token.start, token.end, &token.feature)
+                    })
+                    .collect();
+                // unknown words of the same length can be duplicated like MeCab
+                assert!(!found.is_empty());
+                assert!(found.iter().all(|t| t.cost <= token.cost));
             }
-            entries_vec.push(entries);
         }
+        let tokens = tokenizer.parse(s);
+        for token in tokens.iter() {
+            assert!(all.contains(&token));
+        }
+
+        // conn_cost is from the best previous token
+        for t in all.iter() {
+            let cost = t.conn_cost + t.wcost as i32;
+            assert!(
+                t.cost == cost
+                    || all
+                        .iter()
+                        .any(|p| p.end <= t.start && p.cost + cost == t.cost)
+            );
+        }
+
+        // and from the previous token of the path in the best path and N-best
+        let path_cost = |tokens: &[Token]| {
+            let right_id = tokens.last().unwrap().right_id;
+            tokens
+                .iter()
+                .map(|t| t.conn_cost + t.wcost as i32)
+                .sum::<i32>()
+                + tokenizer.matrix.get_trans_cost(right_id, 0)
+        };
+        assert_eq!(path_cost(&tokens), tokenizer.path_cost(&tokens));
+        for (tokens, cost) in tokenizer.n_best(s).take(20) {
+            assert_eq!(path_cost(&tokens), cost);
+        }
+    }
+
+    // with constraints
+    let morphs =
+        tokenizer.all_morphs_with_constraints("東京都", BoundaryConstraints::new().boundary(6));
+    assert_eq!(surfaces(&morphs), "東,東京 京 都");
 
-        entries_vec
+    // marginal probabilities
+    let mut tokenizer = tokenizer;
+    tokenizer.set_marginal(true);
+    let morphs = tokenizer.all_morphs("東京都");
+    let probs: Vec<Vec<f64>> = morphs
+        .iter()
+        .map(|tokens| tokens.iter().map(|t| t.prob).collect())
+        .collect();
+    for (prob, expected) in probs.iter().flatten().zip([0.5, 0.5, 0.5, 0.5]) {
+        assert!((prob - expected).abs() < 1e-9);
     }
 }
@@ -0,0 +1,271 @@
+/*
+*MIT License
+*
+*Copyright (c) 2020 Hajime Nakagami
+*
+*Permission is hereby granted, free of charge, to any person obtaining a copy
+*of this software and associated documentation files (the "Software"), to deal
+*in the Software without restriction, including without limitation the rights
+*to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+*copies of the Software, and to permit persons to whom the Software is
+*furnished to do so, subject to the following conditions:
+*
+*The above copyright notice and this permission notice shall be included in all
+*copies or substantial portions of the Software.
+*
+*THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+*IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+*FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+*AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+*LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+*OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+*SOFTWARE.
+*/
+use std::collections::HashMap;
+
+use super::Error;
+use super::dic::{DicEntry, Matrix};
+
+/// A word of `UserDictionary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserWord {
+    pub surface: String,
+    pub left_id: u16,
+    pub right_id: u16,
+    pub cost: i16,
+    /// comma separated features
+    pub feature: String,
+}
+
+impl UserWord {
+    pub fn new(surface: &str, left_id: u16, right_id: u16, cost: i16, feature: &str) -> UserWord {
+        UserWord {
+            surface: surface.to_string(),
+            left_id,
+            right_id,
+            cost,
+            feature: feature.to_string(),
+        }
+    }
+}
+
+/// User dictionary built in memory at runtime.
+///
+/// It can be set to a tokenizer with `Tokenizer::set_user_dictionary()`.
+#[derive(Debug, Clone, Default)]
+pub struct UserDictionary {
+    words: HashMap<Vec<u8>, Vec<UserWord>>,
+    max_len: usize,
+    len: usize,
+}
+
+impl UserDictionary {
+    pub fn new() -> UserDictionary {
+        UserDictionary::default()
+    }
+
+    pub fn from_words(words: Vec<UserWord>) -> UserDictionary {
+        let mut dic = UserDictionary::new();
+        for word in words.into_iter() {
+            dic.add(word);
+        }
+        dic
+    }
+
+    /// Add a word. Empty surface is ignored.
+    pub fn add(&mut self, word: UserWord) {
+        if word.surface.is_empty() {
+            return;
+        }
+        self.max_len = self.max_len.max(word.surface.len());
+        self.len += 1;
+        self.words
+            .entry(word.surface.as_bytes().to_vec())
+            .or_default()
+            .push(word);
+    }
+
+    /// number of words
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn words(&self) -> impl Iterator<Item = &UserWord> {
+        self.words.values().flatten()
+    }
+
+    // check the context ids of the words are in matrix.bin
+    pub(crate) fn check_matrix(&self, matrix: &Matrix) -> Result<(), Error> {
+        for word in self.words() {
+            if !matrix.contains(word.left_id, word.right_id) {
+                return Err(Error::InvalidValue {
+                    name: format!("context id of {}", word.surface),
+                    value: format!("{},{}", word.left_id, word.right_id),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // append the words which are prefixes of s to results.
+    // the entries point to the words, so self must outlive them.
+    pub(crate) fn lookup_into(&self, s: &[u8], results: &mut Vec<DicEntry>) {
+        for len in 1..=self.max_len.min(s.len()) {
+            if let Some(words) = self.words.get(&s[..len]) {
+                for word in words.iter() {
+                    results.push(DicEntry {
+                        original_ptr: s.as_ptr(),
+                        original_len: len,
+                        lc_attr: word.left_id,
+                        rc_attr: word.right_id,
+                        posid: 0,
+                        wcost: word.cost,
+                        feature_ptr: word.feature.as_ptr(),
+                        feature_len: word.feature.len(),
+                        skip: false,
+                        unknown: false,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_user_dictionary() {
+    let mut dic = UserDictionary::from_words(vec![
+        UserWord::new("あわび", 1, 1, 1000, "名詞,固有名詞,組織"),
+        UserWord::new("あわ", 1, 1, 2000, "名詞,一般"),
+        UserWord::new("", 1, 1, 2000, "名詞,一般"),
+    ]);
+    dic.add(UserWord::new("あわび", 2, 2, 500, "名詞,一般"));
+    assert_eq!(dic.len(), 3);
+    assert_eq!(dic.words().count(), 3);
+
+    let lookup = |dic: &UserDictionary, s: &[u8]| {
+        let mut entries: Vec<DicEntry> = Vec::new();
+        dic.lookup_into(s, &mut entries);
+        entries
+    };
+    let s = "あわびです".as_bytes();
+    let entries = lookup(&dic, s);
+    assert_eq!(
+        entries
+            .iter()
+            .map(|e| (e.original_len, e.lc_attr, e.wcost))
+            .collect::<Vec<_>>(),
+        vec![(6, 1, 2000), (9, 1, 1000), (9, 2, 500)]
+    );
+    assert!(lookup(&dic, "です".as_bytes()).is_empty());
+    assert!(lookup(&UserDictionary::new(), s).is_empty());
+}
+
+#[cfg(test)]
+use super::tests::fixture_tokenizer;
+
+#[test]
+fn test_runtime_user_dictionary() {
+    let tokenizer = fixture_tokenizer();
+    let tokenizer2 = tokenizer.clone();
+    assert!(tokenizer.user_dictionary().is_empty());
+    assert_eq!(tokenizer.parse("すもももあわび")[0].surface, "すもも");
+
+    let word = tokenizer.user_word("あわび", "名詞,固有名詞").unwrap();
+    let word2 = tokenizer2.user_word("あわび", "名詞,固有名詞").unwrap();
+    assert_eq!(word, word2);
+    assert_eq!((word.left_id, word.right_id, word.cost), (1, 1, 3000));
+    assert_eq!(word.feature, "名詞,固有名詞,*,*,*,*,*,*,*");
+    assert_eq!(tokenizer.user_word("あわび", "名詞,固有"), None);
+    assert_eq!(tokenizer.user_word("あわび", "形容詞"), None);
+
+    tokenizer
+        .set_user_dictionary(UserDictionary::from_words(vec![
+            UserWord::new(
+                "すももも",
+                1,
+                1,
+                1000,
+                "名詞,固有名詞,人名,一般,*,*,すももも,スモモモ,スモモモ",
+            ),
+            word,
+        ]))
+        .unwrap();
+    // shared with the clone
+    for t in [&tokenizer, &tokenizer2] {
+        let tokens = t.parse("すもももあわび");
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| t.surface.as_str())
+                .collect::<Vec<_>>(),
+            vec!["すももも", "あわび"]
+        );
+        assert_eq!(tokens[1].feature, "名詞,固有名詞,*,*,*,*,*,*,*");
+        assert_eq!(tokens[1].byte_range(), 12..21);
+    }
+
+    // swap while parsing in another thread
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for _ in 0..100 {
+                let surfaces = tokenizer2
+                    .parse("すもももあわび")
+                    .into_iter()
+                    .map(|t| t.surface)
+                    .collect::<Vec<_>>();
+                assert!(surfaces[0] == "すももも" || surfaces[0] == "すもも");
+            }
+        });
+        for i in 0..100 {
+            if i % 2 == 0 {
+                tokenizer
+                    .set_user_dictionary(UserDictionary::new())
+                    .unwrap();
+            } else {
+                tokenizer
+                    .set_user_dictionary(UserDictionary::from_words(vec![UserWord::new(
+                        "すももも",
+                        1,
+                        1,
+                        1000,
+                        "名詞",
+                    )]))
+                    .unwrap();
+            }
+        }
+    });
+
+    tokenizer
+        .set_user_dictionary(UserDictionary::new())
+        .unwrap();
+    assert_eq!(tokenizer2.parse("すもももあわび")[0].surface, "すもも");
+
+    // context ids out of matrix.bin (7x7) are rejected, and the current one is kept
+    tokenizer
+        .set_user_dictionary(UserDictionary::from_words(vec![word2.clone()]))
+        .unwrap();
+    for (left_id, right_id) in [(7, 1), (1, 7), (u16::MAX, u16::MAX)] {
+        let result =
+            tokenizer.set_user_dictionary(UserDictionary::from_words(vec![UserWord::new(
+                "すももも",
+                left_id,
+                right_id,
+                1000,
+                "名詞",
+            )]));
+        assert!(matches!(
+            result,
+            Err(Error::InvalidValue { name, .. }) if name == "context id of すももも"
+        ));
+        assert_eq!(tokenizer.user_dictionary().len(), 1);
+        let tokens = tokenizer.parse("すもももあわび");
+        assert_eq!(
+            tokens.last().unwrap().feature,
+            "名詞,固有名詞,*,*,*,*,*,*,*"
+        );
+    }
+}